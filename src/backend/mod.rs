@@ -0,0 +1,18 @@
+//! The async primitives the locks are built on, selected by cargo features.
+//!
+//! Every backend provides the same set of types and functions, so the rest of
+//! the crate does not depend on a specific async runtime. If both backends are
+//! enabled, `async-lock` takes precedence, as `tokio` is enabled by default.
+
+#[cfg(not(any(feature = "tokio", feature = "async-lock")))]
+compile_error!("at least one of the features `tokio` and `async-lock` must be enabled");
+
+#[cfg(feature = "async-lock")]
+mod with_async_lock;
+#[cfg(all(feature = "tokio", not(feature = "async-lock")))]
+mod with_tokio;
+
+#[cfg(feature = "async-lock")]
+pub(crate) use with_async_lock::*;
+#[cfg(all(feature = "tokio", not(feature = "async-lock")))]
+pub(crate) use with_tokio::*;
@@ -0,0 +1,122 @@
+use alloc::sync::Arc;
+
+pub(crate) use async_lock::{
+    Mutex, MutexGuardArc as MutexGuard, RwLock, RwLockReadGuardArc as RwLockReadGuard,
+    RwLockWriteGuardArc as RwLockWriteGuard,
+};
+pub(crate) use event_listener::Event;
+
+use crate::compat::Mutex as BlockingMutex;
+
+/// Lock with shared read access.
+pub(crate) async fn read<T>(lock: Arc<RwLock<T>>) -> RwLockReadGuard<T> {
+    lock.read_arc().await
+}
+
+/// Try lock with shared read access.
+pub(crate) fn try_read<T>(lock: &Arc<RwLock<T>>) -> Option<RwLockReadGuard<T>> {
+    lock.try_read_arc()
+}
+
+/// Lock with exclusive write access.
+pub(crate) async fn write<T>(lock: Arc<RwLock<T>>) -> RwLockWriteGuard<T> {
+    lock.write_arc().await
+}
+
+/// Try lock with exclusive write access.
+pub(crate) fn try_write<T>(lock: &Arc<RwLock<T>>) -> Option<RwLockWriteGuard<T>> {
+    lock.try_write_arc()
+}
+
+/// Atomically downgrade exclusive write access to shared read access.
+pub(crate) fn downgrade<T>(guard: RwLockWriteGuard<T>) -> RwLockReadGuard<T> {
+    RwLockWriteGuard::downgrade(guard)
+}
+
+/// Lock the mutex.
+pub(crate) async fn lock<T>(lock: Arc<Mutex<T>>) -> MutexGuard<T> {
+    lock.lock_arc().await
+}
+
+/// Try lock the mutex.
+pub(crate) fn try_lock<T>(lock: &Arc<Mutex<T>>) -> Option<MutexGuard<T>> {
+    lock.try_lock_arc()
+}
+
+/// A semaphore whose permits can be acquired in batches, which the semaphore
+/// of async-lock does not support. Waiters are not served in FIFO order.
+#[derive(Debug)]
+pub(crate) struct Semaphore {
+    /// Number of permits that are currently available.
+    available: BlockingMutex<usize>,
+    /// Notified whenever permits are released.
+    released: Event,
+}
+
+impl Semaphore {
+    /// Create a new semaphore with this number of permits.
+    pub(crate) fn new(permits: usize) -> Self {
+        Self {
+            available: BlockingMutex::new(permits),
+            released: Event::new(),
+        }
+    }
+}
+
+/// Permits of a [Semaphore], which are released when dropped.
+#[derive(Debug)]
+pub(crate) struct SemaphorePermit {
+    /// The semaphore the permits belong to.
+    semaphore: Arc<Semaphore>,
+    /// The number of permits.
+    n: usize,
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock() += self.n;
+        notify_all(&self.semaphore.released);
+    }
+}
+
+/// Acquire `n` permits of the semaphore.
+pub(crate) async fn acquire_many(semaphore: Arc<Semaphore>, n: u32) -> SemaphorePermit {
+    wait_until(&semaphore.released, || try_acquire_many(&semaphore, n)).await
+}
+
+/// Try acquire `n` permits of the semaphore.
+pub(crate) fn try_acquire_many(semaphore: &Arc<Semaphore>, n: u32) -> Option<SemaphorePermit> {
+    let n = n as usize;
+    let mut available = semaphore.available.lock();
+    if *available < n {
+        return None;
+    }
+    *available -= n;
+    Some(SemaphorePermit {
+        semaphore: Arc::clone(semaphore),
+        n,
+    })
+}
+
+/// Return the number of permits of the semaphore that are currently available.
+pub(crate) fn available_permits(semaphore: &Semaphore) -> usize {
+    *semaphore.available.lock()
+}
+
+/// Wait until `f` returns [Some], calling it again whenever `event` is
+/// notified.
+pub(crate) async fn wait_until<T>(event: &Event, mut f: impl FnMut() -> Option<T>) -> T {
+    loop {
+        // register before checking, so a notification in between is not missed
+        let listener = event.listen();
+        if let Some(value) = f() {
+            return value;
+        }
+        listener.await;
+    }
+}
+
+/// Wake up all tasks waiting for `event`.
+pub(crate) fn notify_all(event: &Event) {
+    event.notify(usize::MAX);
+}
@@ -0,0 +1,80 @@
+use alloc::sync::Arc;
+
+pub(crate) use tokio::sync::{
+    Mutex, Notify as Event, OwnedMutexGuard as MutexGuard, OwnedRwLockReadGuard as RwLockReadGuard,
+    OwnedRwLockWriteGuard as RwLockWriteGuard, OwnedSemaphorePermit as SemaphorePermit, RwLock,
+    Semaphore,
+};
+
+/// Lock with shared read access.
+pub(crate) async fn read<T>(lock: Arc<RwLock<T>>) -> RwLockReadGuard<T> {
+    lock.read_owned().await
+}
+
+/// Try lock with shared read access.
+pub(crate) fn try_read<T>(lock: &Arc<RwLock<T>>) -> Option<RwLockReadGuard<T>> {
+    Arc::clone(lock).try_read_owned().ok()
+}
+
+/// Lock with exclusive write access.
+pub(crate) async fn write<T>(lock: Arc<RwLock<T>>) -> RwLockWriteGuard<T> {
+    lock.write_owned().await
+}
+
+/// Try lock with exclusive write access.
+pub(crate) fn try_write<T>(lock: &Arc<RwLock<T>>) -> Option<RwLockWriteGuard<T>> {
+    Arc::clone(lock).try_write_owned().ok()
+}
+
+/// Atomically downgrade exclusive write access to shared read access.
+pub(crate) fn downgrade<T>(guard: RwLockWriteGuard<T>) -> RwLockReadGuard<T> {
+    guard.downgrade()
+}
+
+/// Lock the mutex.
+pub(crate) async fn lock<T>(lock: Arc<Mutex<T>>) -> MutexGuard<T> {
+    lock.lock_owned().await
+}
+
+/// Try lock the mutex.
+pub(crate) fn try_lock<T>(lock: &Arc<Mutex<T>>) -> Option<MutexGuard<T>> {
+    Arc::clone(lock).try_lock_owned().ok()
+}
+
+/// Acquire `n` permits of the semaphore.
+pub(crate) async fn acquire_many(semaphore: Arc<Semaphore>, n: u32) -> SemaphorePermit {
+    semaphore
+        .acquire_many_owned(n)
+        .await
+        .expect("semaphore is never closed")
+}
+
+/// Try acquire `n` permits of the semaphore.
+pub(crate) fn try_acquire_many(semaphore: &Arc<Semaphore>, n: u32) -> Option<SemaphorePermit> {
+    Arc::clone(semaphore).try_acquire_many_owned(n).ok()
+}
+
+/// Return the number of permits of the semaphore that are currently available.
+pub(crate) fn available_permits(semaphore: &Semaphore) -> usize {
+    semaphore.available_permits()
+}
+
+/// Wait until `f` returns [Some], calling it again whenever `event` is
+/// notified.
+pub(crate) async fn wait_until<T>(event: &Event, mut f: impl FnMut() -> Option<T>) -> T {
+    loop {
+        let notified = event.notified();
+        tokio::pin!(notified);
+        // register before checking, so a notification in between is not missed
+        notified.as_mut().enable();
+        if let Some(value) = f() {
+            return value;
+        }
+        notified.await;
+    }
+}
+
+/// Wake up all tasks waiting for `event`.
+pub(crate) fn notify_all(event: &Event) {
+    event.notify_waiters();
+}
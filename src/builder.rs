@@ -0,0 +1,280 @@
+use alloc::sync::Arc;
+#[cfg(feature = "tracing")]
+use core::fmt;
+#[cfg(feature = "std")]
+use core::time::Duration;
+use core::{hash::BuildHasher, marker::PhantomData};
+
+#[cfg(feature = "deadlock-detection")]
+use crate::deadlock::{Deadlock, Detector};
+#[cfg(feature = "tracing")]
+use crate::trace::KeyFormatter;
+use crate::{
+    compat::RandomState,
+    fairness::FairnessPolicy,
+    map::{CleanupPolicy, Config, LockMap},
+    KeyRwLock,
+};
+
+/// Number of released entries checked on every access, if
+/// [`CleanupPolicy::OnRelease`] is replaced because of a grace period. More
+/// than one, so the queue of released entries shrinks even while some of them
+/// are still in their grace period.
+const GRACE_PERIOD_CLEANUP_BATCH: usize = 4;
+
+/// Builder for a [KeyRwLock] with a custom configuration. Returned by
+/// [`KeyRwLock::builder`].
+///
+/// # Example
+/// ```
+/// # use std::time::Duration;
+/// # use key_rwlock::{CleanupPolicy, FairnessPolicy, KeyRwLock};
+/// # #[cfg(feature = "std")] {
+/// let lock = KeyRwLock::<String>::builder()
+///     .shards(16)
+///     .capacity(1024)
+///     .cleanup(CleanupPolicy::Incremental(4))
+///     .cleanup_threshold(128)
+///     .grace_period(Duration::from_secs(1))
+///     .fairness(FairnessPolicy::WriterPreferring)
+///     .build();
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct KeyRwLockBuilder<K, V = (), S = RandomState> {
+    /// The configuration of the map of locks.
+    config: Config,
+    /// Hashes the keys.
+    hasher: S,
+    /// Reports deadlocks between the holders of the keys, if enabled.
+    #[cfg(feature = "deadlock-detection")]
+    detector: Option<Detector<K>>,
+    /// Formats keys for tracing, if they are recorded.
+    #[cfg(feature = "tracing")]
+    key_formatter: Option<KeyFormatter<K>>,
+    _phantom: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> KeyRwLockBuilder<K, V> {
+    pub(crate) fn new() -> Self {
+        Self {
+            config: Config::default(),
+            hasher: RandomState::default(),
+            #[cfg(feature = "deadlock-detection")]
+            detector: None,
+            #[cfg(feature = "tracing")]
+            key_formatter: None,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<K, V, S> KeyRwLockBuilder<K, V, S> {
+    /// Set the hasher used to hash keys, both to assign them to shards and
+    /// within the shards. Defaults to the
+    /// [RandomState](std::collections::hash_map::RandomState) of the standard
+    /// library, or a hasher that does not need a source of randomness without
+    /// the `std` feature.
+    #[must_use]
+    pub fn hasher<T>(self, hasher: T) -> KeyRwLockBuilder<K, V, T> {
+        KeyRwLockBuilder {
+            config: self.config,
+            hasher,
+            #[cfg(feature = "deadlock-detection")]
+            detector: self.detector,
+            #[cfg(feature = "tracing")]
+            key_formatter: self.key_formatter,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Set the number of shards the map of locks is split into. Keys are
+    /// assigned to shards by their hash and keys in different shards never
+    /// contend with each other. By default, a few times the available
+    /// parallelism is used.
+    ///
+    /// # Panics
+    /// Panics if `shards` is zero.
+    #[must_use]
+    pub fn shards(mut self, shards: usize) -> Self {
+        assert!(shards > 0, "number of shards must not be zero");
+        self.config.shards = shards;
+        self
+    }
+
+    /// Set the number of keys the map of locks can hold without reallocating.
+    /// The capacity is split evenly between the shards.
+    #[must_use]
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.config.capacity = capacity;
+        self
+    }
+
+    /// Set when unused entries are removed automatically. Defaults to
+    /// [`CleanupPolicy::OnRelease`].
+    #[must_use]
+    pub fn cleanup(mut self, cleanup: CleanupPolicy) -> Self {
+        self.config.cleanup = cleanup;
+        self
+    }
+
+    /// Only remove entries automatically while the map holds more than
+    /// `threshold` entries, so up to `threshold` unused entries are kept for
+    /// reuse. Defaults to zero.
+    #[must_use]
+    pub fn cleanup_threshold(mut self, threshold: usize) -> Self {
+        self.config.threshold = threshold;
+        self
+    }
+
+    /// Only remove entries that have been unused for at least `grace_period`,
+    /// so keys that are locked again shortly after their release keep their
+    /// entry. This also applies to [`KeyRwLock::clean`].
+    ///
+    /// As entries are never eligible for removal right when they are released,
+    /// [`CleanupPolicy::OnRelease`] is replaced by
+    /// [`CleanupPolicy::Incremental`] with a grace period, so released entries
+    /// are removed by later accesses once their grace period has passed.
+    #[cfg(feature = "std")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+    #[must_use]
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.config.grace_period = Some(grace_period);
+        self
+    }
+
+    /// Set in which order waiting readers and writers of a key are served.
+    /// Defaults to [`FairnessPolicy::Fifo`].
+    #[must_use]
+    pub fn fairness(mut self, fairness: FairnessPolicy) -> Self {
+        self.config.fairness = fairness;
+        self
+    }
+
+    /// Capture a backtrace for every acquisition, which is returned by
+    /// [`KeyRwLock::inspect`] to find out where a key was locked. Capturing
+    /// backtraces is expensive, so this is disabled by default.
+    #[cfg(feature = "backtrace")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "backtrace")))]
+    #[must_use]
+    pub fn capture_backtraces(mut self, capture_backtraces: bool) -> Self {
+        self.config.capture_backtraces = capture_backtraces;
+        self
+    }
+
+    /// Detect deadlocks between tasks that wait for keys held by each other,
+    /// calling `handler` with the cycle of tasks whenever a task starts
+    /// waiting for a key and thereby closes a cycle. The tasks of the cycle
+    /// keep waiting, so the handler usually logs the deadlock or aborts the
+    /// process.
+    ///
+    /// Tasks are identified by the [TaskId](crate::TaskId) of the scope they
+    /// run in, so only guards created and waited for inside of a
+    /// [`TaskId::scope`](crate::TaskId::scope) are tracked, and a guard is
+    /// attributed to the task that created it, even if it is moved to another
+    /// task. A task only waits for holders whose access conflicts with the
+    /// access it waits for, so waits caused by the fairness policy, e.g. a
+    /// reader queued behind a waiting writer, are not detected. Upgrades of
+    /// upgradable read guards and keys of other locks are not tracked either.
+    #[cfg(feature = "deadlock-detection")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "deadlock-detection")))]
+    #[must_use]
+    pub fn on_deadlock<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Deadlock<K>) + Send + Sync + 'static,
+    {
+        self.detector = Some(Detector::new(handler));
+        self
+    }
+
+    /// Record keys in the `key` field of the spans emitted with the `tracing`
+    /// feature using their [Debug](fmt::Debug) implementation. Keys are not
+    /// recorded by default, as they may be expensive to format or contain
+    /// sensitive data.
+    #[cfg(feature = "tracing")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
+    #[must_use]
+    pub fn trace_keys_debug(self) -> Self
+    where
+        K: fmt::Debug + 'static,
+    {
+        self.trace_keys_with(fmt::Debug::fmt)
+    }
+
+    /// Record keys in the `key` field of the spans emitted with the `tracing`
+    /// feature using their [Display](fmt::Display) implementation.
+    #[cfg(feature = "tracing")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
+    #[must_use]
+    pub fn trace_keys_display(self) -> Self
+    where
+        K: fmt::Display + 'static,
+    {
+        self.trace_keys_with(fmt::Display::fmt)
+    }
+
+    /// Record keys in the `key` field of the spans emitted with the `tracing`
+    /// feature using `formatter`, e.g. to redact parts of them.
+    ///
+    /// # Example
+    /// ```
+    /// # use key_rwlock::KeyRwLock;
+    /// let lock = KeyRwLock::<(String, u64)>::builder()
+    ///     .trace_keys_with(|(_, id), f| write!(f, "user:{id}"))
+    ///     .build();
+    /// ```
+    #[cfg(feature = "tracing")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
+    #[must_use]
+    pub fn trace_keys_with<F>(mut self, formatter: F) -> Self
+    where
+        F: Fn(&K, &mut fmt::Formatter<'_>) -> fmt::Result + Send + Sync + 'static,
+    {
+        self.key_formatter = Some(KeyFormatter::new(formatter));
+        self
+    }
+
+    /// Build a [KeyRwLock] that creates values using [Default] and only
+    /// removes entries if their value is equal to the default value.
+    #[must_use]
+    pub fn build(self) -> KeyRwLock<K, V, S>
+    where
+        V: Default + PartialEq + 'static,
+        S: BuildHasher + Clone,
+    {
+        self.build_with_factory(V::default, |value| *value == V::default())
+    }
+
+    /// Build a [KeyRwLock] that creates values using `factory`. Unused entries
+    /// are only removed if `is_removable` returns `true` for their value.
+    #[must_use]
+    pub fn build_with_factory<F, R>(self, factory: F, is_removable: R) -> KeyRwLock<K, V, S>
+    where
+        S: BuildHasher + Clone,
+        F: Fn() -> V + Send + Sync + 'static,
+        R: Fn(&V) -> bool + Send + Sync + 'static,
+    {
+        let map = LockMap::with_hasher(
+            map_config(self.config),
+            self.hasher,
+            move |_| factory(),
+            is_removable,
+        );
+        #[cfg(feature = "deadlock-detection")]
+        let map = map.with_detector(self.detector);
+        #[cfg(feature = "tracing")]
+        let map = map.with_key_formatter(self.key_formatter);
+        KeyRwLock {
+            inner: Arc::new(map),
+        }
+    }
+}
+
+/// Return the configuration a map of locks is created with, replacing
+/// [`CleanupPolicy::OnRelease`] if a grace period is set.
+pub(crate) fn map_config(mut config: Config) -> Config {
+    if config.grace_period.is_some() && config.cleanup == CleanupPolicy::OnRelease {
+        config.cleanup = CleanupPolicy::Incremental(GRACE_PERIOD_CLEANUP_BATCH);
+    }
+    config
+}
@@ -0,0 +1,103 @@
+//! Blocking mutexes, hashers and clocks, which are taken from std if the `std`
+//! feature is enabled and replaced by alternatives that only need `alloc`
+//! otherwise.
+
+use core::fmt;
+
+#[cfg(feature = "std")]
+pub(crate) use std::collections::hash_map::RandomState;
+
+#[cfg(not(feature = "std"))]
+pub(crate) use hashbrown::hash_map::DefaultHashBuilder as RandomState;
+
+/// A hash map using the default hasher of this module.
+#[cfg(feature = "std")]
+pub(crate) type HashMap<K, V, S = RandomState> = std::collections::HashMap<K, V, S>;
+/// A hash map using the default hasher of this module.
+#[cfg(not(feature = "std"))]
+pub(crate) type HashMap<K, V, S = RandomState> = hashbrown::HashMap<K, V, S>;
+
+#[cfg(feature = "std")]
+type Inner<T> = std::sync::Mutex<T>;
+#[cfg(not(feature = "std"))]
+type Inner<T> = spin::Mutex<T>;
+
+/// The guard of a locked [Mutex].
+#[cfg(feature = "std")]
+pub(crate) type MutexGuard<'a, T> = std::sync::MutexGuard<'a, T>;
+/// The guard of a locked [Mutex].
+#[cfg(not(feature = "std"))]
+pub(crate) type MutexGuard<'a, T> = spin::MutexGuard<'a, T>;
+
+/// A blocking mutex for short critical sections, which never block across an
+/// await point. Poisoning is ignored, as a panicking holder never leaves the
+/// protected data in an inconsistent state.
+pub(crate) struct Mutex<T>(Inner<T>);
+
+impl<T> Mutex<T> {
+    /// Create a new unlocked mutex.
+    pub(crate) fn new(value: T) -> Self {
+        Self(Inner::new(value))
+    }
+
+    /// Lock the mutex, blocking the current thread until it is available.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(feature = "std")]
+        return self
+            .0
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        #[cfg(not(feature = "std"))]
+        return self.0.lock();
+    }
+}
+
+impl<T> Default for Mutex<T>
+where
+    T: Default,
+{
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> fmt::Debug for Mutex<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A point in time. Taken from std if available.
+#[cfg(feature = "std")]
+pub(crate) type Instant = std::time::Instant;
+
+/// A placeholder for a point in time, as there is no clock without std. Grace
+/// periods can only be configured with std, so no durations are ever measured.
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Instant;
+
+#[cfg(not(feature = "std"))]
+impl Instant {
+    /// Return the current point in time.
+    pub(crate) fn now() -> Self {
+        Self
+    }
+
+    /// Return the time elapsed since `earlier`, which is always zero.
+    pub(crate) fn saturating_duration_since(&self, _earlier: Self) -> core::time::Duration {
+        core::time::Duration::ZERO
+    }
+}
+
+/// Return the number of threads that can run in parallel, or one if it is
+/// unknown.
+pub(crate) fn available_parallelism() -> usize {
+    #[cfg(feature = "std")]
+    return std::thread::available_parallelism().map_or(1, usize::from);
+    #[cfg(not(feature = "std"))]
+    return 1;
+}
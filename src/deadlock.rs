@@ -0,0 +1,525 @@
+//! Detection of deadlocks between tasks, which wait for keys held by each
+//! other. Without the `deadlock-detection` feature, all recorders are
+//! zero-sized and do nothing.
+
+#[cfg(feature = "deadlock-detection")]
+pub use imp::Deadlock;
+#[cfg(feature = "deadlock-detection")]
+pub(crate) use imp::{Detector, Holding, Waiting};
+#[cfg(not(feature = "deadlock-detection"))]
+pub(crate) use noop::{Holding, Waiting};
+
+/// The kind of access a task holds or waits for, which decides whether a
+/// holder blocks a waiter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    /// Shared read access.
+    Read,
+    /// Upgradable read access, which excludes other upgradable readers.
+    UpgradableRead,
+    /// Exclusive write access.
+    Write,
+}
+
+impl Access {
+    /// Return whether a holder with this access blocks a waiter with `other`.
+    #[cfg(feature = "deadlock-detection")]
+    fn conflicts(self, other: Self) -> bool {
+        !matches!(
+            (self, other),
+            (Self::Read, Self::Read | Self::UpgradableRead) | (Self::UpgradableRead, Self::Read)
+        )
+    }
+}
+
+#[cfg(feature = "deadlock-detection")]
+mod imp {
+    use std::{
+        collections::{HashMap, HashSet},
+        fmt,
+        hash::{BuildHasher, Hash},
+        sync::Arc,
+    };
+
+    use super::Access;
+    #[cfg(test)]
+    use crate::compat::MutexGuard;
+    use crate::{
+        compat::Mutex,
+        map::{EntryRef, Lock},
+        task::TaskId,
+    };
+
+    /// Handles a detected deadlock.
+    type Handler<K> = Box<dyn Fn(&Deadlock<K>) + Send + Sync>;
+
+    /// A cycle of tasks, each of which waits for a key held by the next one, so
+    /// none of them can ever proceed. Passed to the handler set using
+    /// [`KeyRwLockBuilder::on_deadlock`](crate::KeyRwLockBuilder::on_deadlock).
+    ///
+    /// Tasks are identified by the [TaskId] of the scope they run in.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Deadlock<K> {
+        /// The tasks of the cycle and the key each of them waits for.
+        cycle: Vec<(TaskId, K)>,
+    }
+
+    impl<K> Deadlock<K> {
+        /// Return the tasks of the cycle together with the key each of them
+        /// waits for. The key of each task is held by the next task, and the
+        /// key of the last task is held by the first one, which is the task
+        /// that closed the cycle.
+        #[must_use]
+        pub fn cycle(&self) -> &[(TaskId, K)] {
+            &self.cycle
+        }
+
+        /// Return the tasks of the cycle.
+        pub fn tasks(&self) -> impl Iterator<Item = TaskId> + '_ {
+            self.cycle.iter().map(|(task, _)| *task)
+        }
+
+        /// Return the keys the tasks of the cycle wait for.
+        pub fn keys(&self) -> impl Iterator<Item = &K> {
+            self.cycle.iter().map(|(_, key)| key)
+        }
+    }
+
+    /// Tracks which tasks hold and wait for which keys, and reports cycles to
+    /// a handler.
+    pub(crate) struct Detector<K> {
+        /// The wait-for graph.
+        graph: Mutex<Graph<K>>,
+        /// Called for every detected deadlock.
+        handler: Handler<K>,
+    }
+
+    impl<K> Detector<K> {
+        pub(crate) fn new<F>(handler: F) -> Self
+        where
+            F: Fn(&Deadlock<K>) + Send + Sync + 'static,
+        {
+            Self {
+                graph: Mutex::new(Graph {
+                    holders: HashMap::new(),
+                    waiting: HashMap::new(),
+                }),
+                handler: Box::new(handler),
+            }
+        }
+    }
+
+    impl<K> Detector<K> {
+        /// Lock the wait-for graph.
+        #[cfg(test)]
+        pub(super) fn graph(&self) -> MutexGuard<'_, Graph<K>> {
+            self.graph.lock()
+        }
+    }
+
+    impl<K> fmt::Debug for Detector<K> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Detector").finish_non_exhaustive()
+        }
+    }
+
+    /// A wait-for graph of tasks and keys.
+    #[derive(Debug)]
+    pub(super) struct Graph<K> {
+        /// The tasks holding each key and their access, once for every guard.
+        pub(super) holders: HashMap<K, Vec<(TaskId, Access)>>,
+        /// The key each task is waiting for and the access it waits for.
+        pub(super) waiting: HashMap<TaskId, (K, Access)>,
+    }
+
+    impl<K> Graph<K>
+    where
+        K: Eq + Hash + Clone,
+    {
+        /// Return a cycle of tasks that starts and ends with this task, if
+        /// there is one.
+        pub(super) fn find_cycle(&self, task: TaskId) -> Option<Vec<(TaskId, K)>> {
+            let mut path = Vec::new();
+            self.visit(task, task, &mut path, &mut HashSet::new())
+                .then(|| {
+                    path.into_iter()
+                        .map(|(task, key)| (task, key.clone()))
+                        .collect()
+                })
+        }
+
+        /// Follow the edges from this task to the holders of the key it waits
+        /// for, depth first, until `start` is reached. Only holders whose
+        /// access conflicts with the awaited access block the task. The path
+        /// to `start` is left in `path`.
+        fn visit<'a>(
+            &'a self,
+            start: TaskId,
+            task: TaskId,
+            path: &mut Vec<(TaskId, &'a K)>,
+            visited: &mut HashSet<TaskId>,
+        ) -> bool {
+            let (key, access) = match self.waiting.get(&task) {
+                Some((key, access)) => (key, *access),
+                None => return false,
+            };
+            path.push((task, key));
+            let blockers = self
+                .holders
+                .get(key)
+                .into_iter()
+                .flatten()
+                .filter(|(_, held)| held.conflicts(access));
+            for &(holder, _) in blockers {
+                if holder == start
+                    || (visited.insert(holder) && self.visit(start, holder, path, visited))
+                {
+                    return true;
+                }
+            }
+            path.pop();
+            false
+        }
+    }
+
+    /// Registers the task that created a guard as a holder of its key while
+    /// the guard is alive. Guards created outside of a task scope are not
+    /// tracked.
+    pub(crate) struct Holding<K>
+    where
+        K: Eq + Hash,
+    {
+        /// The detector, task and key of the registration.
+        registration: Option<(Arc<Detector<K>>, TaskId, K)>,
+        /// The access the guard currently holds.
+        access: Access,
+    }
+
+    impl<K> Holding<K>
+    where
+        K: Eq + Hash + Clone,
+    {
+        pub(crate) fn new<L, S>(entry: &EntryRef<K, L, S>, access: Access) -> Self
+        where
+            L: Lock,
+            S: BuildHasher,
+        {
+            let registration = entry
+                .detector()
+                .zip(TaskId::current())
+                .map(|(detector, task)| {
+                    let key = entry.key().clone();
+                    let mut graph = detector.graph.lock();
+                    let holders = graph.holders.entry(key.clone()).or_default();
+                    holders.push((task, access));
+                    drop(graph);
+                    (Arc::clone(detector), task, key)
+                });
+            Self {
+                registration,
+                access,
+            }
+        }
+    }
+
+    impl<K> Holding<K>
+    where
+        K: Eq + Hash,
+    {
+        /// Change the access of the guard, e.g. when it is upgraded.
+        pub(crate) fn set_access(&mut self, access: Access) {
+            if let Some((detector, task, key)) = &self.registration {
+                let mut graph = detector.graph.lock();
+                if let Some(holder) = graph.holders.get_mut(key).and_then(|holders| {
+                    holders
+                        .iter_mut()
+                        .find(|holder| **holder == (*task, self.access))
+                }) {
+                    holder.1 = access;
+                }
+            }
+            self.access = access;
+        }
+    }
+
+    impl<K> Drop for Holding<K>
+    where
+        K: Eq + Hash,
+    {
+        fn drop(&mut self) {
+            let (detector, task, key) = match self.registration.take() {
+                Some(registration) => registration,
+                None => return,
+            };
+            let mut graph = detector.graph.lock();
+            if let Some(holders) = graph.holders.get_mut(&key) {
+                if let Some(idx) = holders
+                    .iter()
+                    .position(|holder| *holder == (task, self.access))
+                {
+                    holders.swap_remove(idx);
+                }
+                if holders.is_empty() {
+                    graph.holders.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Registers the current task as waiting for a key while it is alive.
+    pub(crate) struct Waiting<K> {
+        /// The detector and task of the registration.
+        registration: Option<(Arc<Detector<K>>, TaskId)>,
+    }
+
+    impl<K> Waiting<K>
+    where
+        K: Eq + Hash + Clone,
+    {
+        /// Register the current task as waiting for the key of this entry
+        /// with this access and report a deadlock, if this closes a cycle.
+        pub(crate) fn start<L, S>(entry: &EntryRef<K, L, S>, access: Access) -> Self
+        where
+            L: Lock,
+            S: BuildHasher,
+        {
+            let (detector, task) = match entry.detector().zip(TaskId::current()) {
+                Some(registration) => registration,
+                None => return Self { registration: None },
+            };
+            let mut graph = detector.graph.lock();
+            graph.waiting.insert(task, (entry.key().clone(), access));
+            let cycle = graph.find_cycle(task);
+            drop(graph);
+
+            // the handler is called without holding the graph, so it may lock
+            // or release keys itself
+            if let Some(cycle) = cycle {
+                (detector.handler)(&Deadlock { cycle });
+            }
+            Self {
+                registration: Some((Arc::clone(detector), task)),
+            }
+        }
+
+        /// Unregister the current task, once it has acquired the key.
+        pub(crate) fn stop(self) {}
+    }
+
+    impl<K> Drop for Waiting<K> {
+        fn drop(&mut self) {
+            if let Some((detector, task)) = self.registration.take() {
+                detector.graph.lock().waiting.remove(&task);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "deadlock-detection"))]
+mod noop {
+    use core::{
+        hash::{BuildHasher, Hash},
+        marker::PhantomData,
+    };
+
+    use super::Access;
+    use crate::map::{EntryRef, Lock};
+
+    pub(crate) struct Holding<K>(PhantomData<fn() -> K>);
+
+    impl<K> Holding<K>
+    where
+        K: Eq + Hash,
+    {
+        pub(crate) fn new<L, S>(_entry: &EntryRef<K, L, S>, _access: Access) -> Self
+        where
+            L: Lock,
+            S: BuildHasher,
+        {
+            Self(PhantomData)
+        }
+
+        pub(crate) fn set_access(&mut self, _access: Access) {}
+    }
+
+    pub(crate) struct Waiting<K>(PhantomData<fn() -> K>);
+
+    impl<K> Waiting<K>
+    where
+        K: Eq + Hash,
+    {
+        pub(crate) fn start<L, S>(_entry: &EntryRef<K, L, S>, _access: Access) -> Self
+        where
+            L: Lock,
+            S: BuildHasher,
+        {
+            Self(PhantomData)
+        }
+
+        pub(crate) fn stop(self) {}
+    }
+}
+
+#[cfg(all(test, feature = "deadlock-detection"))]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::{Arc, Mutex as StdMutex},
+    };
+
+    use tokio::sync::{Barrier, Notify};
+
+    use super::{imp::Graph, *};
+    use crate::{task::TaskId, KeyRwLock};
+
+    type Reports = Arc<StdMutex<Vec<Deadlock<&'static str>>>>;
+
+    /// Return a lock that records all detected deadlocks and notifies the
+    /// returned [Notify] about each of them.
+    fn detecting_lock() -> (Arc<KeyRwLock<&'static str>>, Reports, Arc<Notify>) {
+        let reports = Reports::default();
+        let detected = Arc::new(Notify::new());
+        let lock = KeyRwLock::<_>::builder()
+            .on_deadlock({
+                let reports = Arc::clone(&reports);
+                let detected = Arc::clone(&detected);
+                move |deadlock| {
+                    reports.lock().unwrap().push(deadlock.clone());
+                    detected.notify_one();
+                }
+            })
+            .build();
+        (Arc::new(lock), reports, detected)
+    }
+
+    /// Wait until this task is registered as waiting for a key.
+    async fn wait_for_waiter(lock: &KeyRwLock<&'static str>, task: TaskId) {
+        let detector = lock.inner.detector().unwrap();
+        while !detector.graph().waiting.contains_key(&task) {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cycle() {
+        let (lock, reports, detected) = detecting_lock();
+
+        let barrier = Arc::new(Barrier::new(2));
+        let spawn = |held, wanted| {
+            let lock = Arc::clone(&lock);
+            let barrier = Arc::clone(&barrier);
+            let task = TaskId::new();
+            let handle = tokio::spawn(task.scope(async move {
+                let _held = lock.write(held).await;
+                barrier.wait().await;
+                let _wanted = lock.read(wanted).await;
+            }));
+            (task, handle)
+        };
+        let tasks = [spawn("foo", "bar"), spawn("bar", "foo")];
+        detected.notified().await;
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        let mut keys = reports[0].keys().copied().collect::<Vec<_>>();
+        keys.sort_unstable();
+        assert_eq!(keys, ["bar", "foo"]);
+        let ids = tasks.iter().map(|(task, _)| *task).collect::<HashSet<_>>();
+        assert_eq!(reports[0].tasks().collect::<HashSet<_>>(), ids);
+
+        for (_, handle) in tasks {
+            handle.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_self_deadlock() {
+        let (lock, reports, detected) = detecting_lock();
+
+        let task = TaskId::new();
+        let handle = tokio::spawn(task.scope({
+            let lock = Arc::clone(&lock);
+            async move {
+                let _first = lock.write("foo").await;
+                let _second = lock.read("foo").await;
+            }
+        }));
+        detected.notified().await;
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].cycle(), [(task, "foo")]);
+        handle.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_no_cycle() {
+        let (lock, reports, _) = detecting_lock();
+
+        let guard = TaskId::new().scope(lock.write("foo")).await;
+        let tasks = ["foo", "baz"].map(|key| {
+            let lock = Arc::clone(&lock);
+            let task = TaskId::new();
+            let handle = tokio::spawn(task.scope(async move {
+                let _bar = lock.write("bar").await;
+                let _key = lock.write(key).await;
+            }));
+            (task, handle)
+        });
+        // one task waits for "foo" and the other one for "bar"
+        for (task, _) in &tasks {
+            wait_for_waiter(&lock, *task).await;
+        }
+        drop(guard);
+        for (_, handle) in tasks {
+            handle.await.unwrap();
+        }
+
+        assert!(reports.lock().unwrap().is_empty());
+        let graph = lock.inner.detector().unwrap().graph();
+        assert!(graph.holders.is_empty() && graph.waiting.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_and_downgrade() {
+        let (lock, _, _) = detecting_lock();
+
+        let task = TaskId::new();
+        let guard = task.scope(lock.upgradable_read("foo")).await;
+        let holders =
+            |lock: &KeyRwLock<_>| lock.inner.detector().unwrap().graph().holders["foo"].clone();
+        assert_eq!(holders(&lock), [(task, Access::UpgradableRead)]);
+        let guard = guard.upgrade().await;
+        assert_eq!(holders(&lock), [(task, Access::Write)]);
+        let guard = guard.downgrade();
+        assert_eq!(holders(&lock), [(task, Access::Read)]);
+        drop(guard);
+        assert!(lock.inner.detector().unwrap().graph().holders.is_empty());
+    }
+
+    #[test]
+    fn test_compatible_holders() {
+        let (first, second) = (TaskId::new(), TaskId::new());
+        let mut graph = Graph {
+            holders: [
+                ("foo", vec![(first, Access::Read)]),
+                ("bar", vec![(second, Access::Write)]),
+            ]
+            .into_iter()
+            .collect(),
+            waiting: [(first, ("bar", Access::Write))].into_iter().collect(),
+        };
+
+        // readers do not block each other, so there is no cycle
+        for access in [Access::Read, Access::UpgradableRead] {
+            graph.waiting.insert(second, ("foo", access));
+            assert_eq!(graph.find_cycle(second), None);
+        }
+
+        graph.waiting.insert(second, ("foo", Access::Write));
+        assert_eq!(
+            graph.find_cycle(second),
+            Some(vec![(second, "foo"), (first, "bar")])
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use core::fmt;
+#[cfg(feature = "time")]
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::error::Error;
+#[cfg(all(feature = "file", unix))]
+use std::io;
+
+/// Error returned by the non-blocking `try_` methods if the key is already
+/// locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TryLockError(pub(crate) ());
+
+impl fmt::Display for TryLockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("operation would block")
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for TryLockError {}
+
+/// Error returned by
+/// [`KeySemaphore::acquire_many`](crate::semaphore::KeySemaphore::acquire_many)
+/// if more permits are requested than the limit of the key allows, so they
+/// could never be acquired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyPermitsError {
+    /// The number of requested permits.
+    requested: u32,
+    /// The limit of the key.
+    limit: u32,
+}
+
+impl TooManyPermitsError {
+    pub(crate) fn new(requested: u32, limit: u32) -> Self {
+        Self { requested, limit }
+    }
+
+    /// Return the number of requested permits.
+    #[must_use]
+    pub fn requested(&self) -> u32 {
+        self.requested
+    }
+
+    /// Return the limit of the key.
+    #[must_use]
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+}
+
+impl fmt::Display for TooManyPermitsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "requested {} permits, but the limit of the key is {}",
+            self.requested, self.limit
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for TooManyPermitsError {}
+
+/// Error returned by the `_timeout` methods of [KeyRwLock](crate::KeyRwLock)
+/// if the key could not be locked before the timeout elapsed.
+#[cfg(feature = "time")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeoutError<K> {
+    /// The key that could not be locked.
+    key: K,
+    /// How long the acquisition waited before giving up.
+    elapsed: Duration,
+}
+
+#[cfg(feature = "time")]
+impl<K> TimeoutError<K> {
+    pub(crate) fn new(key: K, elapsed: Duration) -> Self {
+        Self { key, elapsed }
+    }
+
+    /// Return the key that could not be locked.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Return the key that could not be locked, consuming the error.
+    #[must_use]
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Return how long the acquisition waited before giving up.
+    #[must_use]
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+}
+
+#[cfg(feature = "time")]
+impl<K> fmt::Display for TimeoutError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "timed out after {:.3}s waiting to lock key",
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+#[cfg(feature = "time")]
+impl<K: fmt::Debug> Error for TimeoutError<K> {}
+
+/// Error returned by the methods of [KeyReadLease](crate::KeyReadLease) and
+/// [KeyWriteLease](crate::KeyWriteLease) once the lease has expired and the
+/// key has been released.
+#[cfg(feature = "lease")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeaseLostError(pub(crate) ());
+
+#[cfg(feature = "lease")]
+impl fmt::Display for LeaseLostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("lease has expired")
+    }
+}
+
+#[cfg(feature = "lease")]
+impl Error for LeaseLostError {}
+
+/// Error returned by the non-blocking `try_` methods of
+/// [FileKeyRwLock](crate::FileKeyRwLock).
+#[cfg(all(feature = "file", unix))]
+#[derive(Debug)]
+pub enum FileTryLockError {
+    /// The key is already locked, either by this or by another process.
+    WouldBlock,
+    /// The lock file could not be opened or locked.
+    Io(io::Error),
+}
+
+#[cfg(all(feature = "file", unix))]
+impl fmt::Display for FileTryLockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WouldBlock => f.write_str("operation would block"),
+            Self::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+#[cfg(all(feature = "file", unix))]
+impl Error for FileTryLockError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::WouldBlock => None,
+            Self::Io(err) => Some(err),
+        }
+    }
+}
+
+#[cfg(all(feature = "file", unix))]
+impl From<io::Error> for FileTryLockError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
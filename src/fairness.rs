@@ -0,0 +1,166 @@
+//! Fairness policies, which decide whether waiting readers or writers of a
+//! key are served first.
+
+use alloc::sync::Arc;
+
+use crate::{
+    backend::{self, Event},
+    compat::{Mutex, MutexGuard},
+    stats::HoldMode,
+};
+
+/// Decides in which order waiting readers and writers of a key are served.
+/// Applies to every key of a [KeyRwLock](crate::KeyRwLock) and is set using
+/// [`KeyRwLockBuilder::fairness`](crate::KeyRwLockBuilder::fairness).
+///
+/// Upgradable readers count as readers. Upgrading and downgrading a guard is
+/// never delayed by the policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FairnessPolicy {
+    /// Serve readers and writers strictly in the order they started waiting,
+    /// so a waiting writer blocks all readers that arrive after it, but not
+    /// the ones that arrived before. Nobody starves. This is the default and
+    /// does not add any overhead.
+    #[default]
+    Fifo,
+    /// Serve waiting writers before readers. While a writer holds or waits for
+    /// a key, new readers have to wait, even if they arrived before a later
+    /// writer. Readers can starve if writers keep arriving.
+    WriterPreferring,
+    /// Serve waiting readers before writers. While readers hold or wait for a
+    /// key, new readers are admitted immediately and writers have to wait
+    /// until all of them are gone, even if they started waiting before the
+    /// readers. Writers can starve if readers keep arriving.
+    ReaderPreferring,
+}
+
+impl FairnessPolicy {
+    /// Return whether a guard in this mode can be admitted to the underlying
+    /// lock, given the number of admitted and waiting guards.
+    fn admits(self, counts: &Counts, mode: HoldMode) -> bool {
+        let [readers, writers] = counts.admitted;
+        match (self, mode) {
+            (Self::Fifo, _) | (Self::WriterPreferring, HoldMode::Write) => true,
+            (Self::WriterPreferring | Self::ReaderPreferring, HoldMode::Read) => writers == 0,
+            (Self::ReaderPreferring, HoldMode::Write) => {
+                readers == 0 && writers == 0 && counts.waiting_readers == 0
+            }
+        }
+    }
+}
+
+/// The guards of a single key known to a [Gate].
+#[derive(Debug, Default)]
+struct Counts {
+    /// Number of admitted readers and writers, which are either waiting for or
+    /// holding the underlying lock.
+    admitted: [usize; 2],
+    /// Number of readers waiting to be admitted. Only counted with
+    /// [`FairnessPolicy::ReaderPreferring`], which does not admit writers
+    /// while readers wait.
+    waiting_readers: usize,
+}
+
+/// Admits guards of a single key to the underlying FIFO lock according to a
+/// [FairnessPolicy], so guards that must not be served next do not queue up
+/// there yet.
+#[derive(Debug, Default)]
+pub(crate) struct Gate {
+    /// Number of admitted and waiting guards.
+    counts: Mutex<Counts>,
+    /// Notified whenever an admitted guard is released or changes its mode,
+    /// or the last waiting reader stops waiting.
+    released: Event,
+}
+
+impl Gate {
+    /// Lock the number of admitted and waiting guards.
+    fn counts(&self) -> MutexGuard<'_, Counts> {
+        self.counts.lock()
+    }
+}
+
+/// Registers a guard as admitted by a [Gate] until it is dropped.
+#[derive(Debug)]
+pub(crate) struct Permit(Option<(Arc<Gate>, HoldMode)>);
+
+impl Permit {
+    /// Wait until the gate admits a guard in this mode.
+    pub(crate) async fn new(gate: &Arc<Gate>, policy: FairnessPolicy, mode: HoldMode) -> Self {
+        let _waiting = WaitingReader::new(gate, policy, mode);
+        backend::wait_until(&gate.released, || Self::try_new(gate, policy, mode)).await
+    }
+
+    /// Admit a guard in this mode, if the gate allows it right now.
+    pub(crate) fn try_new(
+        gate: &Arc<Gate>,
+        policy: FairnessPolicy,
+        mode: HoldMode,
+    ) -> Option<Self> {
+        if policy == FairnessPolicy::Fifo {
+            return Some(Self(None));
+        }
+        let mut counts = gate.counts();
+        if !policy.admits(&counts, mode) {
+            return None;
+        }
+        counts.admitted[mode as usize] += 1;
+        Some(Self(Some((Arc::clone(gate), mode))))
+    }
+
+    /// Change the mode of the admitted guard, e.g. when it is upgraded or
+    /// downgraded.
+    pub(crate) fn set_mode(&mut self, mode: HoldMode) {
+        if let Some((gate, held)) = &mut self.0 {
+            let mut counts = gate.counts();
+            counts.admitted[*held as usize] -= 1;
+            counts.admitted[mode as usize] += 1;
+            *held = mode;
+            drop(counts);
+            backend::notify_all(&gate.released);
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some((gate, mode)) = self.0.take() {
+            gate.counts().admitted[mode as usize] -= 1;
+            backend::notify_all(&gate.released);
+        }
+    }
+}
+
+/// Registers a reader as waiting to be admitted by a [Gate] while it is alive,
+/// so [`FairnessPolicy::ReaderPreferring`] does not admit writers before it.
+/// A reader stops waiting only after it has been admitted, so there is no gap
+/// in which a writer could be admitted.
+struct WaitingReader<'a>(Option<&'a Gate>);
+
+impl<'a> WaitingReader<'a> {
+    /// Register a guard in this mode as waiting, if the policy prefers it.
+    fn new(gate: &'a Gate, policy: FairnessPolicy, mode: HoldMode) -> Self {
+        if policy != FairnessPolicy::ReaderPreferring || mode != HoldMode::Read {
+            return Self(None);
+        }
+        gate.counts().waiting_readers += 1;
+        Self(Some(gate))
+    }
+}
+
+impl Drop for WaitingReader<'_> {
+    fn drop(&mut self) {
+        if let Some(gate) = self.0 {
+            let mut counts = gate.counts();
+            counts.waiting_readers -= 1;
+            let last = counts.waiting_readers == 0;
+            drop(counts);
+            // waiting writers may be admitted now, e.g. if the reader was
+            // cancelled before it was admitted
+            if last {
+                backend::notify_all(&gate.released);
+            }
+        }
+    }
+}
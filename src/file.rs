@@ -0,0 +1,497 @@
+//! Cross-process variant of [KeyRwLock], which locks keys using advisory
+//! `flock` locks on one lock file per key.
+
+use std::{
+    fmt::{self, Write},
+    fs::{self, File, OpenOptions},
+    hash::Hash,
+    io,
+    os::unix::fs::MetadataExt,
+    panic,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use rustix::{
+    fs::{flock, FlockOperation},
+    io::Errno,
+};
+use tokio::{task, time};
+
+use crate::{FileTryLockError, KeyRwLock, KeyRwLockReadGuard, KeyRwLockWriteGuard};
+
+/// Extension of the lock files.
+const EXTENSION: &str = "lock";
+
+/// Maximum length of a file name on common file systems.
+const MAX_FILE_NAME: usize = 255;
+
+/// Delay before the first retry of a lock file that is locked by another
+/// process.
+const MIN_BACKOFF: Duration = Duration::from_millis(1);
+
+/// Maximum delay between two attempts to lock a lock file.
+const MAX_BACKOFF: Duration = Duration::from_millis(100);
+
+/// An async reader-writer lock, that locks based on a key across processes.
+/// Each key is mapped to a lock file in a directory, which is locked with a
+/// shared or exclusive advisory `flock` lock, so all processes using the same
+/// directory exclude each other.
+///
+/// Within a process, acquisitions of the same key are queued using a
+/// [KeyRwLock] first, so at most one writer of a key waits for the file lock,
+/// while every reader that holds the key within the process locks the file
+/// itself. Files are locked without blocking, and a file that is locked by
+/// another process is retried with an exponentially growing delay of up to
+/// 100ms, so waiting tasks do not occupy any threads. Opening and locking
+/// files is offloaded to the blocking thread pool of tokio.
+///
+/// Lock files are named after the [Display](fmt::Display) representation of
+/// the key, in which all characters other than ASCII letters, digits, `-`,
+/// `_` and `.` are percent-encoded. They are not removed when the key is
+/// released, but can be removed using [`FileKeyRwLock::clean`].
+///
+/// As the locks are advisory, they only exclude processes that lock the same
+/// files using `flock`. Locks are released by the operating system if a
+/// process exits, so no lock can be left behind by a crashed process.
+pub struct FileKeyRwLock<K> {
+    /// The directory of the lock files.
+    dir: PathBuf,
+    /// Queues the acquisitions of this process.
+    local: KeyRwLock<K>,
+}
+
+impl<K> FileKeyRwLock<K> {
+    /// Create new instance of a [FileKeyRwLock] that keeps its lock files in
+    /// `dir`, which must exist.
+    #[must_use]
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            local: KeyRwLock::new(),
+        }
+    }
+
+    /// Return the directory of the lock files.
+    #[must_use]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Remove the lock files of all keys that are not locked by any process,
+    /// e.g. files of keys that are no longer used. Returns the number of
+    /// removed files.
+    ///
+    /// Acquisitions that opened a lock file before it was removed notice the
+    /// removal once they have locked it and retry with a new file, so this is
+    /// safe to call while other processes lock keys.
+    pub async fn clean(&self) -> io::Result<usize> {
+        let dir = self.dir.clone();
+        blocking(move || clean_dir(&dir)).await
+    }
+}
+
+impl<K> fmt::Debug for FileKeyRwLock<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileKeyRwLock")
+            .field("dir", &self.dir)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Keys are identified across processes only by the name of their lock file,
+/// so distinct keys must have distinct [Display](fmt::Display)
+/// representations. Keys with the same representation share a lock file and
+/// exclude each other, which deadlocks a task that locks both of them.
+impl<K> FileKeyRwLock<K>
+where
+    K: Eq + Hash + Send + Clone + fmt::Display,
+{
+    /// Lock this key with shared read access, returning a guard.
+    ///
+    /// Waiting for other processes requires the time driver of the tokio
+    /// runtime.
+    pub async fn read(&self, key: K) -> io::Result<FileKeyRwLockReadGuard<K>> {
+        let path = self.path(&key)?;
+        let guard = self.local.read(key).await;
+        let file = poll_lock_file(path, FlockOperation::NonBlockingLockShared).await?;
+        Ok(FileKeyRwLockReadGuard { file, guard })
+    }
+
+    /// Lock this key with exclusive write access, returning a guard.
+    ///
+    /// Waiting for other processes requires the time driver of the tokio
+    /// runtime.
+    pub async fn write(&self, key: K) -> io::Result<FileKeyRwLockWriteGuard<K>> {
+        let path = self.path(&key)?;
+        let guard = self.local.write(key).await;
+        let file = poll_lock_file(path, FlockOperation::NonBlockingLockExclusive).await?;
+        Ok(FileKeyRwLockWriteGuard { file, guard })
+    }
+
+    /// Try lock this key with shared read access, without waiting for other
+    /// holders of the key.
+    pub async fn try_read(&self, key: K) -> Result<FileKeyRwLockReadGuard<K>, FileTryLockError> {
+        let path = self.path(&key)?;
+        let guard = self
+            .local
+            .try_read(key)
+            .await
+            .map_err(|_| FileTryLockError::WouldBlock)?;
+        let file = try_lock_file(path, FlockOperation::NonBlockingLockShared).await?;
+        Ok(FileKeyRwLockReadGuard { file, guard })
+    }
+
+    /// Try lock this key with exclusive write access, without waiting for
+    /// other holders of the key.
+    pub async fn try_write(&self, key: K) -> Result<FileKeyRwLockWriteGuard<K>, FileTryLockError> {
+        let path = self.path(&key)?;
+        let guard = self
+            .local
+            .try_write(key)
+            .await
+            .map_err(|_| FileTryLockError::WouldBlock)?;
+        let file = try_lock_file(path, FlockOperation::NonBlockingLockExclusive).await?;
+        Ok(FileKeyRwLockWriteGuard { file, guard })
+    }
+
+    /// Return the path of the lock file of this key.
+    fn path(&self, key: &K) -> io::Result<PathBuf> {
+        Ok(self.dir.join(file_name(key)?))
+    }
+}
+
+/// RAII structure used to release the shared read access of a key when
+/// dropped. Returned by [`FileKeyRwLock::read`] and
+/// [`FileKeyRwLock::try_read`].
+pub struct FileKeyRwLockReadGuard<K>
+where
+    K: Eq + Hash,
+{
+    /// The locked file. Declared before `guard`, so the file lock is released
+    /// before other tasks of this process can lock the key again.
+    file: File,
+    /// The guard of the key within this process.
+    guard: KeyRwLockReadGuard<K>,
+}
+
+impl<K> FileKeyRwLockReadGuard<K>
+where
+    K: Eq + Hash,
+{
+    /// Return the key this guard has locked.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        self.guard.key()
+    }
+
+    /// Return the locked file, e.g. to store metadata of the key in it.
+    #[must_use]
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl<K> fmt::Debug for FileKeyRwLockReadGuard<K>
+where
+    K: Eq + Hash + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileKeyRwLockReadGuard")
+            .field("key", self.key())
+            .finish()
+    }
+}
+
+/// RAII structure used to release the exclusive write access of a key when
+/// dropped. Returned by [`FileKeyRwLock::write`] and
+/// [`FileKeyRwLock::try_write`].
+pub struct FileKeyRwLockWriteGuard<K>
+where
+    K: Eq + Hash,
+{
+    /// The locked file. Declared before `guard`, so the file lock is released
+    /// before other tasks of this process can lock the key again.
+    file: File,
+    /// The guard of the key within this process.
+    guard: KeyRwLockWriteGuard<K>,
+}
+
+impl<K> FileKeyRwLockWriteGuard<K>
+where
+    K: Eq + Hash,
+{
+    /// Return the key this guard has locked.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        self.guard.key()
+    }
+
+    /// Return the locked file, e.g. to store metadata of the key in it.
+    #[must_use]
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl<K> fmt::Debug for FileKeyRwLockWriteGuard<K>
+where
+    K: Eq + Hash + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileKeyRwLockWriteGuard")
+            .field("key", self.key())
+            .finish()
+    }
+}
+
+/// Return the name of the lock file of this key, percent-encoding all
+/// characters that are not safe in file names. Dots are only encoded at the
+/// start, so the name is never `.` or `..`.
+fn file_name(key: &impl fmt::Display) -> io::Result<String> {
+    let key = key.to_string();
+    let mut name = String::with_capacity(key.len() + EXTENSION.len() + 1);
+    for (idx, byte) in key.bytes().enumerate() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(char::from(byte)),
+            b'.' if idx > 0 => name.push('.'),
+            _ => write!(name, "%{byte:02X}").expect("writing to a string never fails"),
+        }
+    }
+    name.push('.');
+    name.push_str(EXTENSION);
+    if name.len() > MAX_FILE_NAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "key is too long for the name of a lock file",
+        ));
+    }
+    Ok(name)
+}
+
+/// Open the lock file at this path, creating it if necessary, and lock it.
+/// As the file may be removed by [`FileKeyRwLock::clean`] in between, this is
+/// retried until the locked file is still the one at this path.
+fn lock_file(path: &Path, operation: FlockOperation) -> io::Result<File> {
+    loop {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        flock(&file, operation)?;
+        if is_current(&file, path)? {
+            return Ok(file);
+        }
+    }
+}
+
+/// Try lock the lock file at this path on the blocking thread pool, mapping
+/// contention to [`FileTryLockError::WouldBlock`].
+async fn try_lock_file(path: PathBuf, operation: FlockOperation) -> Result<File, FileTryLockError> {
+    blocking(move || lock_file(&path, operation))
+        .await
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::WouldBlock => FileTryLockError::WouldBlock,
+            _ => FileTryLockError::Io(err),
+        })
+}
+
+/// Lock the lock file at this path, retrying with an exponentially growing
+/// delay while it is locked by another process.
+async fn poll_lock_file(path: PathBuf, operation: FlockOperation) -> io::Result<File> {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match try_lock_file(path.clone(), operation).await {
+            Ok(file) => return Ok(file),
+            Err(FileTryLockError::WouldBlock) => {}
+            Err(FileTryLockError::Io(err)) => return Err(err),
+        }
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Check whether this file is still the one at this path, i.e. it has not
+/// been removed or replaced.
+fn is_current(file: &File, path: &Path) -> io::Result<bool> {
+    let locked = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok(locked.dev() == current.dev() && locked.ino() == current.ino()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Remove all lock files in this directory that are not locked. Each file is
+/// locked exclusively while it is removed, so no process can hold it.
+fn clean_dir(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some(EXTENSION.as_ref()) {
+            continue;
+        }
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        match flock(&file, FlockOperation::NonBlockingLockExclusive) {
+            Ok(()) => {}
+            Err(Errno::WOULDBLOCK) => continue,
+            Err(err) => return Err(err.into()),
+        }
+        if !is_current(&file, &path)? {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => removed += 1,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(removed)
+}
+
+/// Run this blocking function on the blocking thread pool of tokio.
+async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(err) => match err.try_into_panic() {
+            Ok(payload) => panic::resume_unwind(payload),
+            Err(err) => Err(io::Error::new(io::ErrorKind::Other, err)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{process, sync::Arc, time::Duration};
+
+    use super::*;
+
+    /// A directory for the lock files of a test, which is removed when it is
+    /// dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("key-rwlock-{}-{name}", process::id()));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name(&"user:42").unwrap(), "user%3A42.lock");
+        assert_eq!(file_name(&"../a.b").unwrap(), "%2E.%2Fa.b.lock");
+        assert_eq!(file_name(&"100%").unwrap(), "100%25.lock");
+        assert!(file_name(&"x".repeat(300)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exclusion_between_instances() {
+        let dir = TempDir::new("exclusion");
+        // separate instances do not share their in-process locks, so they
+        // exclude each other like separate processes
+        let first = FileKeyRwLock::new(&dir.0);
+        let second = FileKeyRwLock::new(&dir.0);
+
+        let foo = first.write("foo").await.unwrap();
+        assert!(matches!(
+            second.try_read("foo").await,
+            Err(FileTryLockError::WouldBlock)
+        ));
+        let _bar = second.try_write("bar").await.unwrap();
+        drop(foo);
+
+        let _foo1 = first.read("foo").await.unwrap();
+        let _foo2 = second.try_read("foo").await.unwrap();
+        assert!(matches!(
+            first.try_write("foo").await,
+            Err(FileTryLockError::WouldBlock)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wait() {
+        let dir = TempDir::new("wait");
+        let first = FileKeyRwLock::new(&dir.0);
+        let second = Arc::new(FileKeyRwLock::new(&dir.0));
+
+        let foo = first.read("foo").await.unwrap();
+        let writer = tokio::spawn({
+            let second = Arc::clone(&second);
+            async move {
+                let guard = second.write("foo").await.unwrap();
+                assert_eq!(guard.key(), &"foo");
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!writer.is_finished());
+        drop(foo);
+        writer.await.unwrap();
+    }
+
+    #[test]
+    fn test_wait_without_blocking_thread() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .max_blocking_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let dir = TempDir::new("wait-without-blocking-thread");
+            let first = FileKeyRwLock::new(&dir.0);
+            let second = Arc::new(FileKeyRwLock::new(&dir.0));
+
+            let foo = first.write("foo").await.unwrap();
+            let waiters = ["foo", "foo", "foo"].map(|key| {
+                let second = Arc::clone(&second);
+                tokio::spawn(async move { drop(second.read(key).await.unwrap()) })
+            });
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            // the waiters must not occupy the only blocking thread
+            let bar = tokio::time::timeout(Duration::from_secs(5), first.write("bar"));
+            drop(bar.await.unwrap().unwrap());
+            drop(foo);
+            for waiter in waiters {
+                waiter.await.unwrap();
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_clean() {
+        let dir = TempDir::new("clean");
+        let lock = FileKeyRwLock::new(&dir.0);
+
+        let foo = lock.write("foo").await.unwrap();
+        drop(lock.read("bar").await.unwrap());
+        fs::write(dir.0.join("other.txt"), "").unwrap();
+
+        assert_eq!(lock.clean().await.unwrap(), 1);
+        assert!(dir.0.join("foo.lock").exists());
+        assert!(!dir.0.join("bar.lock").exists());
+        assert!(dir.0.join("other.txt").exists());
+
+        drop(foo);
+        assert_eq!(lock.clean().await.unwrap(), 1);
+        let _foo = lock.try_write("foo").await.unwrap();
+        assert!(dir.0.join("foo.lock").exists());
+    }
+}
@@ -0,0 +1,796 @@
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    future::Future,
+    hash::{BuildHasher, Hash},
+    ops::{Deref, DerefMut},
+};
+
+use crate::{
+    backend::{self, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    compat::RandomState,
+    deadlock::{Access, Holding, Waiting},
+    error::TryLockError,
+    fairness::{Gate, Permit},
+    map::{EntryRef, Lock},
+    stats::{Hold, HoldMode, Holders, Stopwatch},
+    trace::{Acquiring, Traced},
+    tracking::{Tracked, Tracker},
+};
+
+/// The lock of a single key.
+pub(crate) struct KeyLock<V> {
+    /// The lock protecting the value.
+    value: Arc<RwLock<V>>,
+    /// Held by writers and upgradable readers, so at most one of them can
+    /// access the key at a time and no writer can intervene while an upgradable
+    /// reader upgrades.
+    upgrade: Arc<Mutex<()>>,
+    /// Admits guards to the locks according to the fairness policy.
+    gate: Arc<Gate>,
+    /// Number of guards currently holding the key.
+    holders: Holders,
+    /// The guards currently holding and the tasks waiting for the key.
+    tracker: Tracker,
+}
+
+impl<V> KeyLock<V> {
+    /// Return the number of guards currently holding the key with read and
+    /// write access, respectively.
+    #[cfg(feature = "stats")]
+    pub(crate) fn holders(&self) -> (usize, usize) {
+        (
+            self.holders.count(HoldMode::Read),
+            self.holders.count(HoldMode::Write),
+        )
+    }
+
+    /// Return the guards currently holding and the tasks waiting for the key.
+    #[cfg(feature = "tracking")]
+    pub(crate) fn tracker(&self) -> &Tracker {
+        &self.tracker
+    }
+
+    /// Lock with shared read access.
+    fn read(&self) -> impl Future<Output = RwLockReadGuard<V>> {
+        backend::read(self.value.clone())
+    }
+
+    /// Try lock with shared read access.
+    fn try_read(&self) -> Option<RwLockReadGuard<V>> {
+        backend::try_read(&self.value)
+    }
+
+    /// Lock with upgradable read access.
+    fn upgradable_read(&self) -> impl Future<Output = (MutexGuard<()>, RwLockReadGuard<V>)> {
+        let upgrade = self.upgrade.clone();
+        let value = self.value.clone();
+        async move { (backend::lock(upgrade).await, backend::read(value).await) }
+    }
+
+    /// Try lock with upgradable read access.
+    fn try_upgradable_read(&self) -> Option<(MutexGuard<()>, RwLockReadGuard<V>)> {
+        let upgrade = backend::try_lock(&self.upgrade)?;
+        Some((upgrade, backend::try_read(&self.value)?))
+    }
+
+    /// Lock with exclusive write access.
+    fn write(&self) -> impl Future<Output = (MutexGuard<()>, RwLockWriteGuard<V>)> {
+        let upgrade = self.upgrade.clone();
+        let value = self.value.clone();
+        async move { (backend::lock(upgrade).await, backend::write(value).await) }
+    }
+
+    /// Try lock with exclusive write access.
+    fn try_write(&self) -> Option<(MutexGuard<()>, RwLockWriteGuard<V>)> {
+        let upgrade = backend::try_lock(&self.upgrade)?;
+        Some((upgrade, backend::try_write(&self.value)?))
+    }
+}
+
+impl<V> Lock for KeyLock<V> {
+    type Value = V;
+
+    fn new(value: V) -> Self {
+        Self {
+            value: Arc::new(RwLock::new(value)),
+            upgrade: Arc::default(),
+            gate: Arc::default(),
+            holders: Holders::default(),
+            tracker: Tracker::default(),
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut V {
+        // the inner locks are only cloned by guards, which also hold a
+        // reference to the entry, so they are unique if the entry is unique
+        Arc::get_mut(&mut self.value)
+            .expect("entry is not referenced")
+            .get_mut()
+    }
+}
+
+impl<V: fmt::Debug> fmt::Debug for KeyLock<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+/// Type of a reference to the entry of a key.
+pub(crate) type Entry<K, V, S = RandomState> = EntryRef<K, KeyLock<V>, S>;
+
+/// Lock this entry once the fairness policy admits a guard in this mode,
+/// trying to acquire it without waiting first, so contended acquisitions can
+/// be told apart in the statistics.
+async fn acquire<K, V, S, T, F>(
+    entry: &Entry<K, V, S>,
+    mode: HoldMode,
+    access: Access,
+    owner: Option<String>,
+    try_lock: impl FnOnce(&KeyLock<V>) -> Option<T>,
+    lock: impl FnOnce(&KeyLock<V>) -> F,
+) -> (T, Permit, Tracked, Traced)
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+    F: Future<Output = T>,
+{
+    let gate = &entry.lock().gate;
+    let fairness = entry.config().fairness;
+    let acquiring = Acquiring::start(entry, mode);
+    let mut tracked = Tracked::wait(&entry.lock().tracker, entry.config(), mode, owner);
+    let permit = match Permit::try_new(gate, fairness, mode) {
+        Some(permit) => match try_lock(entry.lock()) {
+            Some(guard) => {
+                entry.metrics().record_fast();
+                tracked.acquired();
+                return (guard, permit, tracked, acquiring.acquired());
+            }
+            None => Some(permit),
+        },
+        None => None,
+    };
+
+    acquiring.contended();
+    let stopwatch = Stopwatch::start();
+    let waiting = Waiting::start(entry, access);
+    let permit = match permit {
+        Some(permit) => permit,
+        None => Permit::new(gate, fairness, mode).await,
+    };
+    let guard = lock(entry.lock()).await;
+    waiting.stop();
+    entry.metrics().record_contended(stopwatch);
+    tracked.acquired();
+    (guard, permit, tracked, acquiring.acquired())
+}
+
+/// Try lock this entry, returning immediately.
+fn try_acquire<K, V, S, T>(
+    entry: &Entry<K, V, S>,
+    mode: HoldMode,
+    owner: Option<String>,
+    try_lock: impl FnOnce(&KeyLock<V>) -> Option<T>,
+) -> Result<(T, Permit, Tracked, Traced), TryLockError>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    let acquiring = Acquiring::start(entry, mode);
+    let permit = Permit::try_new(&entry.lock().gate, entry.config().fairness, mode)
+        .ok_or(TryLockError(()))?;
+    let guard = try_lock(entry.lock()).ok_or(TryLockError(()))?;
+    entry.metrics().record_fast();
+    let mut tracked = Tracked::wait(&entry.lock().tracker, entry.config(), mode, owner);
+    tracked.acquired();
+    Ok((guard, permit, tracked, acquiring.acquired()))
+}
+
+/// RAII structure used to release the shared read access of a key when
+/// dropped. Returned by [`KeyRwLock::read`](crate::KeyRwLock::read) and
+/// [`KeyRwLock::try_read`](crate::KeyRwLock::try_read).
+pub struct KeyRwLockReadGuard<K, V = (), S = RandomState>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// The guard of the underlying lock. Declared before `entry`, so the lock
+    /// is released before the entry is checked for removal.
+    guard: RwLockReadGuard<V>,
+    /// Keeps this guard admitted by the fairness policy.
+    _permit: Permit,
+    /// Registers this guard as a holder of the key.
+    _hold: Hold,
+    /// Registers the task of this guard as a holder of the key for deadlock
+    /// detection.
+    _holding: Holding<K>,
+    /// Registers this guard as a holder of the key for inspection.
+    _tracked: Tracked,
+    /// Keeps the span of this guard open until it is released.
+    _traced: Traced,
+    /// The entry this guard belongs to.
+    entry: Entry<K, V, S>,
+}
+
+impl<K, V, S> KeyRwLockReadGuard<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    /// Lock this entry with shared read access.
+    pub(crate) async fn acquire(entry: Entry<K, V, S>, owner: Option<String>) -> Self {
+        let (guard, permit, tracked, traced) = acquire(
+            &entry,
+            HoldMode::Read,
+            Access::Read,
+            owner,
+            KeyLock::try_read,
+            KeyLock::read,
+        )
+        .await;
+        Self::new(guard, permit, tracked, traced, entry)
+    }
+
+    /// Try lock this entry with shared read access.
+    pub(crate) fn try_acquire(
+        entry: Entry<K, V, S>,
+        owner: Option<String>,
+    ) -> Result<Self, TryLockError> {
+        let (guard, permit, tracked, traced) =
+            try_acquire(&entry, HoldMode::Read, owner, KeyLock::try_read)?;
+        Ok(Self::new(guard, permit, tracked, traced, entry))
+    }
+
+    fn new(
+        guard: RwLockReadGuard<V>,
+        permit: Permit,
+        tracked: Tracked,
+        traced: Traced,
+        entry: Entry<K, V, S>,
+    ) -> Self {
+        Self {
+            guard,
+            _permit: permit,
+            _hold: Hold::new(&entry.lock().holders, HoldMode::Read),
+            _holding: Holding::new(&entry, Access::Read),
+            _tracked: tracked,
+            _traced: traced,
+            entry,
+        }
+    }
+}
+
+impl<K, V, S> KeyRwLockReadGuard<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// Return the key this guard has locked.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        self.entry.key()
+    }
+}
+
+impl<K, V, S> Deref for KeyRwLockReadGuard<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.guard
+    }
+}
+
+impl<K, V, S> fmt::Debug for KeyRwLockReadGuard<K, V, S>
+where
+    K: Eq + Hash + fmt::Debug,
+    S: BuildHasher,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyRwLockReadGuard")
+            .field("key", self.key())
+            .field("value", &**self)
+            .finish()
+    }
+}
+
+/// RAII structure used to release the upgradable read access of a key when
+/// dropped. Returned by
+/// [`KeyRwLock::upgradable_read`](crate::KeyRwLock::upgradable_read) and
+/// [`KeyRwLock::try_upgradable_read`](crate::KeyRwLock::try_upgradable_read).
+///
+/// Upgradable read access coexists with shared read access, but excludes
+/// writers and other upgradable readers of the same key.
+pub struct KeyRwLockUpgradableReadGuard<K, V = (), S = RandomState>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// The guard of the underlying lock. Declared before `entry`, so the lock
+    /// is released before the entry is checked for removal.
+    guard: RwLockReadGuard<V>,
+    /// The guard that excludes writers and other upgradable readers.
+    upgrade: MutexGuard<()>,
+    /// Keeps this guard admitted by the fairness policy.
+    permit: Permit,
+    /// Registers this guard as a holder of the key.
+    hold: Hold,
+    /// Registers the task of this guard as a holder of the key for deadlock
+    /// detection.
+    holding: Holding<K>,
+    /// Registers this guard as a holder of the key for inspection.
+    tracked: Tracked,
+    /// Keeps the span of this guard open until it is released.
+    traced: Traced,
+    /// The entry this guard belongs to.
+    entry: Entry<K, V, S>,
+}
+
+impl<K, V, S> KeyRwLockUpgradableReadGuard<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    /// Lock this entry with upgradable read access.
+    pub(crate) async fn acquire(entry: Entry<K, V, S>, owner: Option<String>) -> Self {
+        let ((upgrade, guard), permit, tracked, traced) = acquire(
+            &entry,
+            HoldMode::Read,
+            Access::UpgradableRead,
+            owner,
+            KeyLock::try_upgradable_read,
+            KeyLock::upgradable_read,
+        )
+        .await;
+        Self::new(upgrade, guard, permit, tracked, traced, entry)
+    }
+
+    /// Try lock this entry with upgradable read access.
+    pub(crate) fn try_acquire(
+        entry: Entry<K, V, S>,
+        owner: Option<String>,
+    ) -> Result<Self, TryLockError> {
+        let ((upgrade, guard), permit, tracked, traced) =
+            try_acquire(&entry, HoldMode::Read, owner, KeyLock::try_upgradable_read)?;
+        Ok(Self::new(upgrade, guard, permit, tracked, traced, entry))
+    }
+
+    fn new(
+        upgrade: MutexGuard<()>,
+        guard: RwLockReadGuard<V>,
+        permit: Permit,
+        tracked: Tracked,
+        traced: Traced,
+        entry: Entry<K, V, S>,
+    ) -> Self {
+        Self {
+            guard,
+            upgrade,
+            permit,
+            hold: Hold::new(&entry.lock().holders, HoldMode::Read),
+            holding: Holding::new(&entry, Access::UpgradableRead),
+            tracked,
+            traced,
+            entry,
+        }
+    }
+}
+
+impl<K, V, S> KeyRwLockUpgradableReadGuard<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// Return the key this guard has locked.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        self.entry.key()
+    }
+
+    /// Upgrade to exclusive write access, waiting for all other readers of
+    /// this key to release their access. No writer can lock the key in the
+    /// meantime, so the value is unchanged when this function returns.
+    ///
+    /// If the returned future is dropped before completion, the access to the
+    /// key is released.
+    pub async fn upgrade(self) -> KeyRwLockWriteGuard<K, V, S> {
+        let Self {
+            guard,
+            upgrade,
+            mut permit,
+            mut hold,
+            mut holding,
+            mut tracked,
+            mut traced,
+            entry,
+        } = self;
+        drop(guard);
+        let guard = backend::write(entry.lock().value.clone()).await;
+        permit.set_mode(HoldMode::Write);
+        hold.set_mode(HoldMode::Write);
+        holding.set_access(Access::Write);
+        tracked.set_mode(HoldMode::Write);
+        traced.set_mode(HoldMode::Write);
+        KeyRwLockWriteGuard {
+            guard,
+            upgrade,
+            permit,
+            hold,
+            holding,
+            tracked,
+            traced,
+            entry,
+        }
+    }
+
+    /// Downgrade to shared read access, allowing writers and upgradable
+    /// readers to lock this key again.
+    #[must_use]
+    pub fn downgrade(self) -> KeyRwLockReadGuard<K, V, S> {
+        let Self {
+            guard,
+            upgrade,
+            permit,
+            hold,
+            mut holding,
+            tracked,
+            traced,
+            entry,
+        } = self;
+        drop(upgrade);
+        holding.set_access(Access::Read);
+        KeyRwLockReadGuard {
+            guard,
+            _permit: permit,
+            _hold: hold,
+            _holding: holding,
+            _tracked: tracked,
+            _traced: traced,
+            entry,
+        }
+    }
+}
+
+impl<K, V, S> Deref for KeyRwLockUpgradableReadGuard<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.guard
+    }
+}
+
+impl<K, V, S> fmt::Debug for KeyRwLockUpgradableReadGuard<K, V, S>
+where
+    K: Eq + Hash + fmt::Debug,
+    S: BuildHasher,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyRwLockUpgradableReadGuard")
+            .field("key", self.key())
+            .field("value", &**self)
+            .finish()
+    }
+}
+
+/// RAII structure used to release the exclusive write access of a key when
+/// dropped. Returned by [`KeyRwLock::write`](crate::KeyRwLock::write) and
+/// [`KeyRwLock::try_write`](crate::KeyRwLock::try_write).
+pub struct KeyRwLockWriteGuard<K, V = (), S = RandomState>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// The guard of the underlying lock. Declared before `entry`, so the lock
+    /// is released before the entry is checked for removal.
+    guard: RwLockWriteGuard<V>,
+    /// The guard that excludes other writers and upgradable readers.
+    upgrade: MutexGuard<()>,
+    /// Keeps this guard admitted by the fairness policy.
+    permit: Permit,
+    /// Registers this guard as a holder of the key.
+    hold: Hold,
+    /// Registers the task of this guard as a holder of the key for deadlock
+    /// detection.
+    holding: Holding<K>,
+    /// Registers this guard as a holder of the key for inspection.
+    tracked: Tracked,
+    /// Keeps the span of this guard open until it is released.
+    traced: Traced,
+    /// The entry this guard belongs to.
+    entry: Entry<K, V, S>,
+}
+
+impl<K, V, S> KeyRwLockWriteGuard<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    /// Lock this entry with exclusive write access.
+    pub(crate) async fn acquire(entry: Entry<K, V, S>, owner: Option<String>) -> Self {
+        let ((upgrade, guard), permit, tracked, traced) = acquire(
+            &entry,
+            HoldMode::Write,
+            Access::Write,
+            owner,
+            KeyLock::try_write,
+            KeyLock::write,
+        )
+        .await;
+        Self::new(upgrade, guard, permit, tracked, traced, entry)
+    }
+
+    /// Try lock this entry with exclusive write access.
+    pub(crate) fn try_acquire(
+        entry: Entry<K, V, S>,
+        owner: Option<String>,
+    ) -> Result<Self, TryLockError> {
+        let ((upgrade, guard), permit, tracked, traced) =
+            try_acquire(&entry, HoldMode::Write, owner, KeyLock::try_write)?;
+        Ok(Self::new(upgrade, guard, permit, tracked, traced, entry))
+    }
+
+    fn new(
+        upgrade: MutexGuard<()>,
+        guard: RwLockWriteGuard<V>,
+        permit: Permit,
+        tracked: Tracked,
+        traced: Traced,
+        entry: Entry<K, V, S>,
+    ) -> Self {
+        Self {
+            guard,
+            upgrade,
+            permit,
+            hold: Hold::new(&entry.lock().holders, HoldMode::Write),
+            holding: Holding::new(&entry, Access::Write),
+            tracked,
+            traced,
+            entry,
+        }
+    }
+}
+
+impl<K, V, S> KeyRwLockWriteGuard<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// Return the key this guard has locked.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        self.entry.key()
+    }
+
+    /// Atomically downgrade to shared read access, so no writer can lock the
+    /// key in between.
+    #[must_use]
+    pub fn downgrade(self) -> KeyRwLockReadGuard<K, V, S> {
+        let Self {
+            guard,
+            upgrade,
+            mut permit,
+            mut hold,
+            mut holding,
+            mut tracked,
+            mut traced,
+            entry,
+        } = self;
+        let guard = backend::downgrade(guard);
+        drop(upgrade);
+        permit.set_mode(HoldMode::Read);
+        hold.set_mode(HoldMode::Read);
+        holding.set_access(Access::Read);
+        tracked.set_mode(HoldMode::Read);
+        traced.set_mode(HoldMode::Read);
+        KeyRwLockReadGuard {
+            guard,
+            _permit: permit,
+            _hold: hold,
+            _holding: holding,
+            _tracked: tracked,
+            _traced: traced,
+            entry,
+        }
+    }
+
+    /// Atomically downgrade to upgradable read access, so no writer can lock
+    /// the key in between.
+    #[must_use]
+    pub fn downgrade_to_upgradable(self) -> KeyRwLockUpgradableReadGuard<K, V, S> {
+        let Self {
+            guard,
+            upgrade,
+            mut permit,
+            mut hold,
+            mut holding,
+            mut tracked,
+            mut traced,
+            entry,
+        } = self;
+        let guard = backend::downgrade(guard);
+        permit.set_mode(HoldMode::Read);
+        hold.set_mode(HoldMode::Read);
+        holding.set_access(Access::UpgradableRead);
+        tracked.set_mode(HoldMode::Read);
+        traced.set_mode(HoldMode::Read);
+        KeyRwLockUpgradableReadGuard {
+            guard,
+            upgrade,
+            permit,
+            hold,
+            holding,
+            tracked,
+            traced,
+            entry,
+        }
+    }
+}
+
+impl<K, V, S> Deref for KeyRwLockWriteGuard<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.guard
+    }
+}
+
+impl<K, V, S> DerefMut for KeyRwLockWriteGuard<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    fn deref_mut(&mut self) -> &mut V {
+        &mut self.guard
+    }
+}
+
+impl<K, V, S> fmt::Debug for KeyRwLockWriteGuard<K, V, S>
+where
+    K: Eq + Hash + fmt::Debug,
+    S: BuildHasher,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyRwLockWriteGuard")
+            .field("key", self.key())
+            .field("value", &**self)
+            .finish()
+    }
+}
+
+/// A guard for either shared read or exclusive write access of a key.
+#[derive(Debug)]
+pub enum KeyRwLockGuard<K, V = (), S = RandomState>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// Shared read access.
+    Read(KeyRwLockReadGuard<K, V, S>),
+    /// Exclusive write access.
+    Write(KeyRwLockWriteGuard<K, V, S>),
+}
+
+impl<K, V, S> KeyRwLockGuard<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// Return the key this guard has locked.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        match self {
+            Self::Read(guard) => guard.key(),
+            Self::Write(guard) => guard.key(),
+        }
+    }
+
+    /// Return a mutable reference to the value, if this guard has exclusive
+    /// write access.
+    pub fn get_mut(&mut self) -> Option<&mut V> {
+        match self {
+            Self::Read(_) => None,
+            Self::Write(guard) => Some(guard),
+        }
+    }
+}
+
+impl<K, V, S> Deref for KeyRwLockGuard<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        match self {
+            Self::Read(guard) => guard,
+            Self::Write(guard) => guard,
+        }
+    }
+}
+
+/// RAII structure used to release the access of multiple keys when dropped.
+/// Returned by [`KeyRwLock::lock_set`](crate::KeyRwLock::lock_set) and related
+/// methods.
+#[derive(Debug)]
+pub struct KeyRwLockSetGuard<K, V = (), S = RandomState>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// The guards of all keys, sorted by key.
+    guards: Vec<KeyRwLockGuard<K, V, S>>,
+}
+
+impl<K, V, S> KeyRwLockSetGuard<K, V, S>
+where
+    K: Eq + Hash + Ord,
+    S: BuildHasher,
+{
+    /// Create a new set guard from guards that are sorted by key.
+    pub(crate) fn new(guards: Vec<KeyRwLockGuard<K, V, S>>) -> Self {
+        Self { guards }
+    }
+
+    /// Return the value of this key, if it has been locked.
+    #[must_use]
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.find(key).map(|idx| &*self.guards[idx])
+    }
+
+    /// Return a mutable reference to the value of this key, if it has been
+    /// locked with exclusive write access.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.find(key).and_then(|idx| self.guards[idx].get_mut())
+    }
+
+    /// Return an iterator over the locked keys in ascending order.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.guards.iter().map(KeyRwLockGuard::key)
+    }
+
+    /// Return an iterator over the guards of all locked keys in ascending
+    /// order.
+    pub fn iter(&self) -> impl Iterator<Item = &KeyRwLockGuard<K, V, S>> {
+        self.guards.iter()
+    }
+
+    /// Return the number of locked keys.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.guards.len()
+    }
+
+    /// Check whether no keys are locked.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.guards.is_empty()
+    }
+
+    /// Split this guard into the guards of the individual keys.
+    #[must_use]
+    pub fn into_inner(self) -> Vec<KeyRwLockGuard<K, V, S>> {
+        self.guards
+    }
+
+    /// Return the index of the guard for this key.
+    fn find(&self, key: &K) -> Option<usize> {
+        self.guards
+            .binary_search_by(|guard| guard.key().cmp(key))
+            .ok()
+    }
+}
@@ -0,0 +1,468 @@
+//! Hierarchical reader-writer lock for path-like keys, which implements
+//! multi-granularity locking.
+//!
+//! Keys are sequences of segments, e.g. the components of a file path or the
+//! levels `[database, table, row]` of a resource tree. Locking a path also
+//! takes an intention lock on each of its ancestors, so locking a node
+//! excludes conflicting locks on its whole subtree, while unrelated subtrees
+//! can still be locked independently.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{fmt, hash::Hash};
+
+pub use crate::error::TryLockError;
+use crate::{
+    backend::{self, Event},
+    compat::Mutex,
+    map::{Config, EntryRef, Lock, LockMap},
+    LockMode,
+};
+
+/// A reader-writer lock for hierarchical keys. Based on a
+/// [HashMap](std::collections::HashMap) of nodes, one for each path that is
+/// currently locked and each of its ancestors.
+///
+/// Locking a path with shared read access takes an intention shared (IS) lock
+/// on all ancestors and a shared (S) lock on the path itself. Exclusive write
+/// access takes intention exclusive (IX) locks on all ancestors and an
+/// exclusive (X) lock on the path. As a result:
+/// - Reading a path excludes writers of the path, its ancestors and its
+///   descendants.
+/// - Writing a path excludes all other access to the path, its ancestors and
+///   its descendants.
+/// - Paths where neither is a prefix of the other never contend, even if they
+///   share ancestors.
+///
+/// The empty path is the root of the tree, so locking it locks every path.
+/// Nodes are locked top-down, which prevents deadlocks between acquisitions of
+/// single paths. Fairness is not guaranteed: a constant stream of readers can
+/// starve a writer.
+///
+/// # Example
+/// ```
+/// use key_rwlock::hierarchy::HierarchicalRwLock;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let lock = HierarchicalRwLock::new();
+///
+/// let _row = lock.write(["db", "users", "42"]).await;
+/// // other rows of the same table can still be locked
+/// assert!(lock.try_write(["db", "users", "43"]).await.is_ok());
+/// // but the whole table cannot
+/// assert!(lock.try_read(["db", "users"]).await.is_err());
+/// # }
+/// ```
+pub struct HierarchicalRwLock<T> {
+    /// The map of nodes, keyed by their full path.
+    inner: Arc<LockMap<Vec<T>, Node>>,
+}
+
+/// The mode in which a single node is locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeMode {
+    /// A descendant is locked with shared read access.
+    IntentionShared,
+    /// A descendant is locked with exclusive write access.
+    IntentionExclusive,
+    /// The node is locked with shared read access.
+    Shared,
+    /// The node is locked with exclusive write access.
+    Exclusive,
+}
+
+impl NodeMode {
+    /// Return the mode in which a node is locked when acquiring a path in this
+    /// mode. `target` is `true` for the last node of the path.
+    fn new(mode: LockMode, target: bool) -> Self {
+        match (mode, target) {
+            (LockMode::Read, false) => Self::IntentionShared,
+            (LockMode::Write, false) => Self::IntentionExclusive,
+            (LockMode::Read, true) => Self::Shared,
+            (LockMode::Write, true) => Self::Exclusive,
+        }
+    }
+
+    /// Return whether this mode conflicts with the modes currently held on a
+    /// node.
+    fn conflicts(self, counts: &[usize; 4]) -> bool {
+        let [is, ix, s, x] = *counts;
+        match self {
+            Self::IntentionShared => x > 0,
+            Self::IntentionExclusive => s > 0 || x > 0,
+            Self::Shared => ix > 0 || x > 0,
+            Self::Exclusive => is + ix + s + x > 0,
+        }
+    }
+}
+
+/// A single node of the tree, which can be held in any [NodeMode].
+struct Node {
+    /// Number of holders per [NodeMode].
+    counts: Mutex<[usize; 4]>,
+    /// Notified whenever a holder releases the node.
+    released: Event,
+    /// Nodes do not protect a value.
+    value: (),
+}
+
+impl Node {
+    /// Lock the node in this mode, if it does not conflict with the current
+    /// holders.
+    fn try_lock(&self, mode: NodeMode) -> bool {
+        let mut counts = self.counts.lock();
+        if mode.conflicts(&counts) {
+            return false;
+        }
+        counts[mode as usize] += 1;
+        true
+    }
+
+    /// Lock the node in this mode, waiting until it does not conflict with the
+    /// current holders.
+    async fn lock(&self, mode: NodeMode) {
+        backend::wait_until(&self.released, || self.try_lock(mode).then_some(())).await;
+    }
+
+    /// Release the node from this mode and wake up all waiters.
+    fn unlock(&self, mode: NodeMode) {
+        let mut counts = self.counts.lock();
+        counts[mode as usize] -= 1;
+        drop(counts);
+        backend::notify_all(&self.released);
+    }
+}
+
+impl Lock for Node {
+    type Value = ();
+
+    fn new(value: ()) -> Self {
+        Self {
+            counts: Mutex::new([0; 4]),
+            released: Event::new(),
+            value,
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut () {
+        &mut self.value
+    }
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts = self.counts.lock();
+        f.debug_struct("Node")
+            .field(
+                "intention_shared",
+                &counts[NodeMode::IntentionShared as usize],
+            )
+            .field(
+                "intention_exclusive",
+                &counts[NodeMode::IntentionExclusive as usize],
+            )
+            .field("shared", &counts[NodeMode::Shared as usize])
+            .field("exclusive", &counts[NodeMode::Exclusive as usize])
+            .finish()
+    }
+}
+
+impl<T> Default for HierarchicalRwLock<T> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(LockMap::new(Config::default(), |_| (), |_| true)),
+        }
+    }
+}
+
+impl<T> fmt::Debug for HierarchicalRwLock<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HierarchicalRwLock")
+            .field("shards", &self.inner.shards())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> HierarchicalRwLock<T> {
+    /// Create new instance of a [HierarchicalRwLock]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<T> HierarchicalRwLock<T>
+where
+    T: Eq + Hash + Send + Clone,
+{
+    /// Lock this path with shared read access, returning a guard. Waits until
+    /// no writer holds the path, one of its ancestors or one of its
+    /// descendants.
+    pub async fn read(&self, path: impl IntoIterator<Item = T>) -> HierarchicalRwLockGuard<T> {
+        self.acquire(path, LockMode::Read).await
+    }
+
+    /// Lock this path with exclusive write access, returning a guard. Waits
+    /// until no other task holds the path, one of its ancestors or one of its
+    /// descendants.
+    pub async fn write(&self, path: impl IntoIterator<Item = T>) -> HierarchicalRwLockGuard<T> {
+        self.acquire(path, LockMode::Write).await
+    }
+
+    /// Attempt to lock this path with shared read access, without waiting.
+    pub async fn try_read(
+        &self,
+        path: impl IntoIterator<Item = T>,
+    ) -> Result<HierarchicalRwLockGuard<T>, TryLockError> {
+        self.try_acquire(path, LockMode::Read)
+    }
+
+    /// Attempt to lock this path with exclusive write access, without waiting.
+    pub async fn try_write(
+        &self,
+        path: impl IntoIterator<Item = T>,
+    ) -> Result<HierarchicalRwLockGuard<T>, TryLockError> {
+        self.try_acquire(path, LockMode::Write)
+    }
+
+    /// Remove all nodes that are not in use.
+    ///
+    /// This is usually not necessary, as nodes are removed automatically when
+    /// they are released.
+    pub async fn clean(&self) {
+        self.inner.clean_up();
+    }
+
+    /// Lock all nodes from the root down to this path. If the future is
+    /// dropped, the nodes locked so far are released by the partial guard.
+    async fn acquire(
+        &self,
+        path: impl IntoIterator<Item = T>,
+        mode: LockMode,
+    ) -> HierarchicalRwLockGuard<T> {
+        let path = path.into_iter().collect::<Vec<_>>();
+        let mut guard = HierarchicalRwLockGuard::new(path.len(), mode);
+        for depth in 0..=path.len() {
+            let node_mode = NodeMode::new(mode, depth == path.len());
+            let entry = self.inner.entry_ref(&path[..depth]);
+            entry.lock().lock(node_mode).await;
+            guard.holds.push(NodeHold {
+                entry,
+                mode: node_mode,
+            });
+        }
+        guard
+    }
+
+    /// Lock all nodes from the root down to this path without waiting.
+    fn try_acquire(
+        &self,
+        path: impl IntoIterator<Item = T>,
+        mode: LockMode,
+    ) -> Result<HierarchicalRwLockGuard<T>, TryLockError> {
+        let path = path.into_iter().collect::<Vec<_>>();
+        let mut guard = HierarchicalRwLockGuard::new(path.len(), mode);
+        for depth in 0..=path.len() {
+            let node_mode = NodeMode::new(mode, depth == path.len());
+            let entry = self.inner.entry_ref(&path[..depth]);
+            if !entry.lock().try_lock(node_mode) {
+                return Err(TryLockError(()));
+            }
+            guard.holds.push(NodeHold {
+                entry,
+                mode: node_mode,
+            });
+        }
+        Ok(guard)
+    }
+}
+
+/// A node held in a specific mode, which is released when dropped.
+struct NodeHold<T>
+where
+    T: Eq + Hash,
+{
+    /// The entry of the node, which keeps it in the map.
+    entry: EntryRef<Vec<T>, Node>,
+    /// The mode in which the node is held.
+    mode: NodeMode,
+}
+
+impl<T> Drop for NodeHold<T>
+where
+    T: Eq + Hash,
+{
+    fn drop(&mut self) {
+        // release the node before the entry is dropped, so it can be removed
+        self.entry.lock().unlock(self.mode);
+    }
+}
+
+/// RAII structure used to release the access to a path and the intention
+/// locks on its ancestors when dropped. Returned by
+/// [`HierarchicalRwLock::read`] and [`HierarchicalRwLock::write`].
+pub struct HierarchicalRwLockGuard<T>
+where
+    T: Eq + Hash,
+{
+    /// The held nodes, from the root down to the locked path.
+    holds: Vec<NodeHold<T>>,
+    /// The mode in which the path is locked.
+    mode: LockMode,
+}
+
+impl<T> HierarchicalRwLockGuard<T>
+where
+    T: Eq + Hash,
+{
+    /// Create a guard that does not hold any node yet.
+    fn new(depth: usize, mode: LockMode) -> Self {
+        Self {
+            holds: Vec::with_capacity(depth + 1),
+            mode,
+        }
+    }
+
+    /// Return the path this guard holds.
+    #[must_use]
+    pub fn path(&self) -> &[T] {
+        self.holds
+            .last()
+            .expect("guard holds at least the root")
+            .entry
+            .key()
+    }
+
+    /// Return whether this guard holds shared read or exclusive write access.
+    #[must_use]
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+}
+
+impl<T> Drop for HierarchicalRwLockGuard<T>
+where
+    T: Eq + Hash,
+{
+    fn drop(&mut self) {
+        // release bottom-up, so the path is never held without its ancestors
+        while self.holds.pop().is_some() {}
+    }
+}
+
+impl<T> fmt::Debug for HierarchicalRwLockGuard<T>
+where
+    T: Eq + Hash + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HierarchicalRwLockGuard")
+            .field("path", &self.path())
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_basic_funcionality() {
+        let lock = HierarchicalRwLock::new();
+
+        let _ab = lock.write(["a", "b"]).await;
+        let _c = lock.read(["c"]).await;
+
+        // the path itself
+        assert!(lock.try_read(["a", "b"]).await.is_err());
+        assert!(lock.try_write(["a", "b"]).await.is_err());
+        // ancestors
+        assert!(lock.try_read(["a"]).await.is_err());
+        assert!(lock.try_write([]).await.is_err());
+        // descendants
+        assert!(lock.try_read(["a", "b", "x"]).await.is_err());
+        // siblings
+        assert!(lock.try_write(["a", "c"]).await.is_ok());
+        assert!(lock.try_read(["a", "c", "x"]).await.is_ok());
+
+        // readers of a subtree only exclude writers
+        assert!(lock.try_read(["c", "x"]).await.is_ok());
+        assert!(lock.try_write(["c", "x"]).await.is_err());
+        assert!(lock.try_read([]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_root() {
+        let lock = HierarchicalRwLock::new();
+
+        let root = lock.write([]).await;
+        assert_eq!(root.path(), &[] as &[&str]);
+        assert_eq!(root.mode(), LockMode::Write);
+        assert!(lock.try_read(["a"]).await.is_err());
+        drop(root);
+
+        let root = lock.read([]).await;
+        assert!(lock.try_read(["a"]).await.is_ok());
+        assert!(lock.try_write(["a"]).await.is_err());
+        drop(root);
+
+        assert!(lock.try_write(["a"]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_clean_up() {
+        let lock = HierarchicalRwLock::new();
+
+        let guard = lock.write(["a", "b", "c"]).await;
+        assert_eq!(guard.path(), &["a", "b", "c"]);
+        assert_eq!(lock.inner.len(), 4);
+        let sibling = lock.try_write(["a", "x"]).await;
+        assert!(sibling.is_ok());
+        assert_eq!(lock.inner.len(), 5);
+        drop(sibling);
+        assert!(lock.try_read(["a", "b"]).await.is_err());
+        assert_eq!(lock.inner.len(), 4);
+        drop(guard);
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wait_for_ancestor() {
+        let lock = Arc::new(HierarchicalRwLock::new());
+
+        let table = lock.read(["db", "table"]).await;
+        let task = tokio::spawn({
+            let lock = Arc::clone(&lock);
+            async move {
+                let _row = lock.write(["db", "table", "row"]).await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!task.is_finished());
+
+        drop(table);
+        task.await.unwrap();
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_acquirer() {
+        let lock = HierarchicalRwLock::new();
+
+        let row = lock.write(["db", "table", "row"]).await;
+        let acquire = lock.read(["db", "table", "row"]);
+        assert!(tokio::time::timeout(Duration::from_millis(10), acquire)
+            .await
+            .is_err());
+
+        // the intention locks taken by the cancelled acquirer are released
+        drop(row);
+        assert!(lock.try_write(["db"]).await.is_ok());
+        assert_eq!(lock.inner.len(), 0);
+    }
+}
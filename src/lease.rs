@@ -0,0 +1,523 @@
+use std::{
+    fmt,
+    hash::{BuildHasher, Hash},
+    ops::{Deref, DerefMut},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    sync::watch,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+use crate::{
+    compat::{Mutex, RandomState},
+    KeyRwLock, KeyRwLockReadGuard, KeyRwLockWriteGuard, LeaseLostError, TryLockError,
+};
+
+/// Shared read access to a key that is revoked once its time to live has
+/// elapsed without being renewed, so a holder that hangs or leaks the lease
+/// cannot block the key forever. Returned by [`KeyRwLock::read_lease`] and
+/// [`KeyRwLock::try_read_lease`].
+///
+/// The access is released when the lease is dropped. After revocation,
+/// [`renew`](Self::renew) and [`with`](Self::with) fail and
+/// [`lost`](Self::lost) completes, so the holder can stop its work.
+pub struct KeyReadLease<K, V = (), S = RandomState>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// The lease of the read guard.
+    inner: Lease<K, KeyRwLockReadGuard<K, V, S>>,
+}
+
+/// Exclusive write access to a key that is revoked once its time to live has
+/// elapsed without being renewed, so a holder that hangs or leaks the lease
+/// cannot block the key forever. Returned by [`KeyRwLock::write_lease`] and
+/// [`KeyRwLock::try_write_lease`].
+///
+/// The access is released when the lease is dropped. After revocation,
+/// [`renew`](Self::renew) and the value accessors fail and
+/// [`lost`](Self::lost) completes, so the holder can stop its work.
+pub struct KeyWriteLease<K, V = (), S = RandomState>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// The lease of the write guard.
+    inner: Lease<K, KeyRwLockWriteGuard<K, V, S>>,
+}
+
+/// A lease of a guard, which is shared by [KeyReadLease] and [KeyWriteLease].
+struct Lease<K, G> {
+    /// The key this lease has locked.
+    key: K,
+    /// How far each renewal extends the deadline.
+    ttl: Duration,
+    /// The state shared with the expiry task.
+    state: Arc<State<G>>,
+    /// Whether the lease has been revoked.
+    lost: watch::Receiver<bool>,
+    /// The task that revokes the lease at its deadline.
+    expiry: JoinHandle<()>,
+}
+
+/// The state of a lease shared with its expiry task.
+struct State<G> {
+    /// The guard and deadline of the lease. Only locked briefly and never
+    /// while the value of the key is accessed, so accessors can use the lease
+    /// and the expiry task never waits for them.
+    held: Mutex<Held<G>>,
+    /// Notifies the holder once the lease has been revoked.
+    lost: watch::Sender<bool>,
+}
+
+/// The parts of the state of a lease that are changed by renewal and
+/// revocation.
+struct Held<G> {
+    /// The guard of the key, which is cloned by accessors of the value and
+    /// taken out while the value is accessed mutably. `None` once the lease
+    /// has been revoked or while the value is accessed mutably.
+    guard: Option<Arc<G>>,
+    /// Whether the lease has been revoked.
+    revoked: bool,
+    /// When the lease is revoked unless it is renewed before.
+    deadline: Instant,
+}
+
+impl<K, G> Lease<K, G>
+where
+    G: Send + Sync + 'static,
+{
+    fn new(key: K, guard: G, ttl: Duration) -> Self {
+        let (sender, lost) = watch::channel(false);
+        let state = Arc::new(State {
+            held: Mutex::new(Held {
+                guard: Some(Arc::new(guard)),
+                revoked: false,
+                deadline: Instant::now() + ttl,
+            }),
+            lost: sender,
+        });
+        let expiry = tokio::spawn(expire(Arc::clone(&state)));
+        Self {
+            key,
+            ttl,
+            state,
+            lost,
+            expiry,
+        }
+    }
+}
+
+impl<K, G> Lease<K, G> {
+    /// Return when the lease will be revoked unless it is renewed, or `None`
+    /// if it has been revoked already.
+    fn deadline(&self) -> Option<Instant> {
+        let held = self.state.held.lock();
+        (!held.revoked).then_some(held.deadline)
+    }
+
+    /// Extend the lease to the time to live from now.
+    fn renew(&self) -> Result<(), LeaseLostError> {
+        let mut held = self.state.held.lock();
+        if held.revoked {
+            return Err(LeaseLostError(()));
+        }
+        held.deadline = Instant::now() + self.ttl;
+        Ok(())
+    }
+
+    /// Check whether the lease has been revoked.
+    fn is_lost(&self) -> bool {
+        *self.lost.borrow()
+    }
+
+    /// Wait until the lease has been revoked.
+    async fn lost(&self) {
+        let mut lost = self.lost.clone();
+        while !*lost.borrow_and_update() {
+            // the sender lives as long as the lease, so this cannot fail
+            if lost.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Call `f` with a reference to the value of the key. The guard is
+    /// cloned, so the state is not locked while `f` runs.
+    fn with<R>(&self, f: impl FnOnce(&G::Target) -> R) -> Result<R, LeaseLostError>
+    where
+        G: Deref,
+    {
+        let guard = self.state.held.lock().guard.clone();
+        let guard = guard.ok_or(LeaseLostError(()))?;
+        Ok(f(&guard))
+    }
+
+    /// Call `f` with a mutable reference to the value of the key. The guard
+    /// is taken out of the state while `f` runs, so it is only released once
+    /// `f` returns, even if the lease is revoked in between.
+    fn with_mut<R>(&mut self, f: impl FnOnce(&mut G::Target) -> R) -> Result<R, LeaseLostError>
+    where
+        G: DerefMut,
+    {
+        let mut guard = self
+            .state
+            .held
+            .lock()
+            .guard
+            .take()
+            .ok_or(LeaseLostError(()))?;
+        // accessors of the value borrow the lease, so none of them can hold a
+        // clone of the guard while it is borrowed mutably
+        let value = Arc::get_mut(&mut guard).expect("guard of a mutably borrowed lease is shared");
+        let result = f(value);
+        let mut held = self.state.held.lock();
+        if !held.revoked {
+            held.guard = Some(guard);
+        }
+        // otherwise the guard is dropped after `held`, so the key is released
+        // without holding the state
+        Ok(result)
+    }
+}
+
+impl<K, G> Drop for Lease<K, G> {
+    fn drop(&mut self) {
+        self.expiry.abort();
+        let guard = self.state.held.lock().guard.take();
+        drop(guard);
+    }
+}
+
+/// Revoke the lease once its deadline has passed without being renewed.
+async fn expire<G>(state: Arc<State<G>>) {
+    loop {
+        let deadline = state.held.lock().deadline;
+        time::sleep_until(deadline).await;
+        let mut held = state.held.lock();
+        if held.deadline <= Instant::now() {
+            held.revoked = true;
+            let guard = held.guard.take();
+            drop(held);
+            // release the key before notifying the holder
+            drop(guard);
+            state.lost.send_replace(true);
+            return;
+        }
+    }
+}
+
+impl<K, V, S> KeyReadLease<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// Return the key this lease has locked.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        &self.inner.key
+    }
+
+    /// Return the time to live of the lease.
+    #[must_use]
+    pub fn ttl(&self) -> Duration {
+        self.inner.ttl
+    }
+
+    /// Return when the lease will be revoked unless it is renewed, or `None`
+    /// if it has been revoked already.
+    #[must_use]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.inner.deadline()
+    }
+
+    /// Extend the lease to the time to live from now.
+    ///
+    /// # Errors
+    /// Returns an error if the lease has been revoked already.
+    pub fn renew(&self) -> Result<(), LeaseLostError> {
+        self.inner.renew()
+    }
+
+    /// Check whether the lease has been revoked.
+    #[must_use]
+    pub fn is_lost(&self) -> bool {
+        self.inner.is_lost()
+    }
+
+    /// Wait until the lease has been revoked. Never completes if the lease is
+    /// renewed in time.
+    pub async fn lost(&self) {
+        self.inner.lost().await;
+    }
+
+    /// Call `f` with a reference to the value of the key. If the lease is
+    /// revoked while `f` runs, the key is only released once `f` returns.
+    ///
+    /// # Errors
+    /// Returns an error if the lease has been revoked already.
+    pub fn with<R>(&self, f: impl FnOnce(&V) -> R) -> Result<R, LeaseLostError> {
+        self.inner.with(f)
+    }
+}
+
+impl<K, V, S> fmt::Debug for KeyReadLease<K, V, S>
+where
+    K: Eq + Hash + fmt::Debug,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyReadLease")
+            .field("key", self.key())
+            .field("ttl", &self.ttl())
+            .field("lost", &self.is_lost())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K, V, S> KeyWriteLease<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// Return the key this lease has locked.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        &self.inner.key
+    }
+
+    /// Return the time to live of the lease.
+    #[must_use]
+    pub fn ttl(&self) -> Duration {
+        self.inner.ttl
+    }
+
+    /// Return when the lease will be revoked unless it is renewed, or `None`
+    /// if it has been revoked already.
+    #[must_use]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.inner.deadline()
+    }
+
+    /// Extend the lease to the time to live from now.
+    ///
+    /// # Errors
+    /// Returns an error if the lease has been revoked already.
+    pub fn renew(&self) -> Result<(), LeaseLostError> {
+        self.inner.renew()
+    }
+
+    /// Check whether the lease has been revoked.
+    #[must_use]
+    pub fn is_lost(&self) -> bool {
+        self.inner.is_lost()
+    }
+
+    /// Wait until the lease has been revoked. Never completes if the lease is
+    /// renewed in time.
+    pub async fn lost(&self) {
+        self.inner.lost().await;
+    }
+
+    /// Call `f` with a reference to the value of the key. If the lease is
+    /// revoked while `f` runs, the key is only released once `f` returns.
+    ///
+    /// # Errors
+    /// Returns an error if the lease has been revoked already.
+    pub fn with<R>(&self, f: impl FnOnce(&V) -> R) -> Result<R, LeaseLostError> {
+        self.inner.with(f)
+    }
+
+    /// Call `f` with a mutable reference to the value of the key. If the
+    /// lease is revoked while `f` runs, the key is only released once `f`
+    /// returns.
+    ///
+    /// # Errors
+    /// Returns an error if the lease has been revoked already.
+    pub fn with_mut<R>(&mut self, f: impl FnOnce(&mut V) -> R) -> Result<R, LeaseLostError> {
+        self.inner.with_mut(f)
+    }
+}
+
+impl<K, V, S> fmt::Debug for KeyWriteLease<K, V, S>
+where
+    K: Eq + Hash + fmt::Debug,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyWriteLease")
+            .field("key", self.key())
+            .field("ttl", &self.ttl())
+            .field("lost", &self.is_lost())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K, V, S> KeyRwLock<K, V, S>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+    S: BuildHasher + Send + Sync + 'static,
+{
+    /// Lock this key with shared read access for a lease that is revoked
+    /// unless it is renewed within `ttl`. The time to live starts once the
+    /// key has been locked.
+    ///
+    /// # Panics
+    /// Panics if called outside of a tokio runtime.
+    pub async fn read_lease(&self, key: K, ttl: Duration) -> KeyReadLease<K, V, S> {
+        let guard = self.read(key.clone()).await;
+        KeyReadLease {
+            inner: Lease::new(key, guard, ttl),
+        }
+    }
+
+    /// Lock this key with exclusive write access for a lease that is revoked
+    /// unless it is renewed within `ttl`. The time to live starts once the
+    /// key has been locked.
+    ///
+    /// # Panics
+    /// Panics if called outside of a tokio runtime.
+    pub async fn write_lease(&self, key: K, ttl: Duration) -> KeyWriteLease<K, V, S> {
+        let guard = self.write(key.clone()).await;
+        KeyWriteLease {
+            inner: Lease::new(key, guard, ttl),
+        }
+    }
+
+    /// Try lock this key with shared read access for a lease, returning
+    /// immediately.
+    ///
+    /// # Panics
+    /// Panics if called outside of a tokio runtime.
+    pub async fn try_read_lease(
+        &self,
+        key: K,
+        ttl: Duration,
+    ) -> Result<KeyReadLease<K, V, S>, TryLockError> {
+        let guard = self.try_read(key.clone()).await?;
+        Ok(KeyReadLease {
+            inner: Lease::new(key, guard, ttl),
+        })
+    }
+
+    /// Try lock this key with exclusive write access for a lease, returning
+    /// immediately.
+    ///
+    /// # Panics
+    /// Panics if called outside of a tokio runtime.
+    pub async fn try_write_lease(
+        &self,
+        key: K,
+        ttl: Duration,
+    ) -> Result<KeyWriteLease<K, V, S>, TryLockError> {
+        let guard = self.try_write(key.clone()).await?;
+        Ok(KeyWriteLease {
+            inner: Lease::new(key, guard, ttl),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::KeyRwLock;
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let lock = KeyRwLock::<_, u32>::default();
+        let mut lease = lock.write_lease("foo", Duration::from_millis(50)).await;
+        lease.with_mut(|value| *value = 1).unwrap();
+        assert!(lock.try_read("foo").await.is_err());
+
+        // waiters proceed once the lease has expired
+        let guard = lock.write("foo").await;
+        assert_eq!(*guard, 1);
+        lease.lost().await;
+        assert!(lease.is_lost());
+        assert!(lease.renew().is_err());
+        assert!(lease.with(|value| *value).is_err());
+        assert!(lease.with_mut(|value| *value).is_err());
+        assert_eq!(lease.deadline(), None);
+        drop(guard);
+        drop(lease);
+        assert!(lock.try_write("foo").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_renew_and_release() {
+        let lock = KeyRwLock::new();
+        let lease = lock.write_lease(1, Duration::from_millis(100)).await;
+        for _ in 0..5 {
+            tokio::time::sleep(Duration::from_millis(40)).await;
+            lease.renew().unwrap();
+            assert!(lock.try_read(1).await.is_err());
+        }
+        assert!(!lease.is_lost());
+        assert!(lease.deadline().is_some());
+
+        drop(lease);
+        assert!(lock.try_write(1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_read_leases() {
+        let lock = KeyRwLock::<_>::new();
+        let first = lock.read_lease(1, Duration::from_millis(50)).await;
+        let second = lock
+            .try_read_lease(1, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(lock
+            .try_write_lease(1, Duration::from_secs(60))
+            .await
+            .is_err());
+
+        // the key stays locked until the longer lease is released
+        first.lost().await;
+        assert!(lock.try_write(1).await.is_err());
+        assert!(!second.is_lost());
+        drop(second);
+        assert!(lock.try_write(1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_access_within_accessor() {
+        let lock = KeyRwLock::<_, u32>::default();
+        let mut lease = lock.write_lease(1, Duration::from_secs(60)).await;
+        lease.with_mut(|value| *value = 2).unwrap();
+
+        let sum = lease
+            .with(|value| {
+                lease.renew().unwrap();
+                assert!(lease.deadline().is_some());
+                lease.with(|inner| *inner + *value).unwrap()
+            })
+            .unwrap();
+        assert_eq!(sum, 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_expiry_during_with_mut() {
+        let lock = KeyRwLock::<_, u32>::default();
+        let mut lease = lock.write_lease(1, Duration::from_millis(20)).await;
+
+        // the expiry task revokes the lease without waiting for the closure,
+        // but the key is only released once the closure has returned
+        lease
+            .with_mut(|value| {
+                thread::sleep(Duration::from_millis(200));
+                *value = 1;
+            })
+            .unwrap();
+        assert!(lease.is_lost());
+        assert_eq!(*lock.try_read(1).await.unwrap(), 1);
+        assert!(lease.with(|value| *value).is_err());
+    }
+}
@@ -1,130 +1,628 @@
 #![doc = include_str!("../README.md")]
+#![cfg_attr(docsrs, feature(doc_cfg))]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![forbid(unsafe_code)]
 #![warn(clippy::dbg_macro, clippy::use_debug)]
 #![warn(missing_docs, missing_debug_implementations, clippy::todo)]
 
-use std::{
-    collections::HashMap,
-    hash::Hash,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
+extern crate alloc;
+
+#[cfg(feature = "tracking")]
+use alloc::string::String;
+use alloc::{borrow::ToOwned, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    borrow::Borrow,
+    fmt,
+    hash::{BuildHasher, Hash},
 };
+#[cfg(feature = "time")]
+use core::{future::Future, time::Duration};
+
+use compat::RandomState;
+use guard::KeyLock;
+use map::LockMap;
+#[cfg(feature = "time")]
+use tokio::time::Instant;
 
-use tokio::sync::{Mutex, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock, TryLockError};
+pub use builder::KeyRwLockBuilder;
+#[cfg(feature = "deadlock-detection")]
+#[cfg_attr(docsrs, doc(cfg(feature = "deadlock-detection")))]
+pub use deadlock::Deadlock;
+#[cfg(all(feature = "file", unix))]
+#[cfg_attr(docsrs, doc(cfg(all(feature = "file", unix))))]
+pub use error::FileTryLockError;
+#[cfg(feature = "lease")]
+#[cfg_attr(docsrs, doc(cfg(feature = "lease")))]
+pub use error::LeaseLostError;
+#[cfg(feature = "time")]
+#[cfg_attr(docsrs, doc(cfg(feature = "time")))]
+pub use error::TimeoutError;
+pub use error::TryLockError;
+pub use fairness::FairnessPolicy;
+#[cfg(all(feature = "file", unix))]
+#[cfg_attr(docsrs, doc(cfg(all(feature = "file", unix))))]
+pub use file::{FileKeyRwLock, FileKeyRwLockReadGuard, FileKeyRwLockWriteGuard};
+pub use guard::{
+    KeyRwLockGuard, KeyRwLockReadGuard, KeyRwLockSetGuard, KeyRwLockUpgradableReadGuard,
+    KeyRwLockWriteGuard,
+};
+#[cfg(feature = "lease")]
+#[cfg_attr(docsrs, doc(cfg(feature = "lease")))]
+pub use lease::{KeyReadLease, KeyWriteLease};
+pub use map::CleanupPolicy;
+pub use mutex::{KeyMutex, KeyMutexBuilder, KeyMutexGuard};
+#[cfg(feature = "reaper")]
+#[cfg_attr(docsrs, doc(cfg(feature = "reaper")))]
+pub use reaper::ReaperHandle;
+#[cfg(feature = "stats")]
+#[cfg_attr(docsrs, doc(cfg(feature = "stats")))]
+pub use stats::{Stats, WaitHistogram};
+#[cfg(any(feature = "deadlock-detection", feature = "tracking"))]
+#[cfg_attr(
+    docsrs,
+    doc(cfg(any(feature = "deadlock-detection", feature = "tracking")))
+)]
+pub use task::{TaskId, TaskScope};
+#[cfg(feature = "tracking")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tracking")))]
+pub use tracking::{Acquisition, LockInfo};
+
+mod backend;
+mod builder;
+mod compat;
+mod deadlock;
+mod error;
+mod fairness;
+#[cfg(all(feature = "file", unix))]
+mod file;
+mod guard;
+pub mod hierarchy;
+#[cfg(feature = "lease")]
+mod lease;
+mod map;
+mod mutex;
+pub mod range;
+#[cfg(feature = "reaper")]
+mod reaper;
+pub mod semaphore;
+mod stats;
+#[cfg(feature = "sync")]
+#[cfg_attr(docsrs, doc(cfg(feature = "sync")))]
+pub mod sync;
+#[cfg(any(feature = "deadlock-detection", feature = "tracking"))]
+mod task;
+mod trace;
+mod tracking;
 
 /// An async reader-writer lock, that locks based on a key, while allowing other
-/// keys to lock independently. Based on a [HashMap] of [RwLock]s.
-#[derive(Debug)]
-pub struct KeyRwLock<K> {
-    /// The inner map of locks for specific keys.
-    locks: Mutex<HashMap<K, Arc<RwLock<()>>>>,
-    /// Number of lock accesses.
-    accesses: AtomicUsize,
+/// keys to lock independently. Based on a [HashMap](std::collections::HashMap)
+/// of async reader-writer locks.
+///
+/// Each key owns a value of type `V`, which is created lazily on first access
+/// and can be accessed through the returned guards. The entry for a key is
+/// removed as soon as the last guard or pending acquirer for this key goes away
+/// and its value is removable.
+///
+/// Keys are hashed using `S`, which can be set using
+/// [`KeyRwLock::with_hasher`] or [`KeyRwLockBuilder::hasher`].
+pub struct KeyRwLock<K, V = (), S = RandomState> {
+    /// The map of locks, which is shared with all guards.
+    inner: Arc<LockMap<K, KeyLock<V>, S>>,
+}
+
+/// The kind of access to lock a key with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LockMode {
+    /// Shared read access.
+    Read,
+    /// Exclusive write access.
+    Write,
 }
 
-impl<K> Default for KeyRwLock<K> {
+impl<K, V, S> Default for KeyRwLock<K, V, S>
+where
+    V: Default + PartialEq + 'static,
+    S: BuildHasher + Clone + Default,
+{
     fn default() -> Self {
-        Self {
-            locks: Mutex::default(),
-            accesses: AtomicUsize::default(),
-        }
+        Self::with_hasher(S::default())
     }
 }
 
-impl<K> KeyRwLock<K>
+impl<K, V, S> fmt::Debug for KeyRwLock<K, V, S>
 where
-    K: Eq + Hash + Send + Clone,
+    K: fmt::Debug,
+    V: fmt::Debug,
 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyRwLock")
+            .field("shards", &self.inner.shards())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K> KeyRwLock<K> {
     /// Create new instance of a [KeyRwLock]
+    ///
+    /// To protect a value for each key, use [`KeyRwLock::default`] (values are
+    /// created using [Default] and only removed if they are equal to the
+    /// default value) or [`KeyRwLock::with_factory`].
     #[must_use]
     pub fn new() -> Self {
         Self::default()
     }
+}
+
+impl<K, V> KeyRwLock<K, V>
+where
+    V: Default + PartialEq + 'static,
+{
+    /// Create new instance of a [KeyRwLock] whose map of locks is split into
+    /// this number of shards. Keys are assigned to shards by their hash and
+    /// keys in different shards never contend with each other. By default, a
+    /// few times the available parallelism is used.
+    ///
+    /// # Panics
+    /// Panics if `shards` is zero.
+    #[must_use]
+    pub fn with_shards(shards: usize) -> Self {
+        Self::builder().shards(shards).build()
+    }
+}
 
-    /// Lock this key with shared read access, returning a guard. Cleans up
-    /// locks every 1000 accesses.
-    pub async fn read(&self, key: K) -> OwnedRwLockReadGuard<()> {
-        let mut locks = self.locks.lock().await;
+impl<K, V, S> KeyRwLock<K, V, S>
+where
+    V: Default + PartialEq + 'static,
+    S: BuildHasher + Clone,
+{
+    /// Create new instance of a [KeyRwLock] that hashes keys using `hasher`,
+    /// e.g. a faster hasher for keys that are already uniformly distributed
+    /// or a deterministic hasher for reproducible tests.
+    #[must_use]
+    pub fn with_hasher(hasher: S) -> Self {
+        KeyRwLockBuilder::new().hasher(hasher).build()
+    }
 
-        if self.accesses.fetch_add(1, Ordering::Relaxed) % 1000 == 0 {
-            Self::clean_up(&mut locks);
-        }
+    /// Create new instance of a [KeyRwLock] that hashes keys using `hasher`
+    /// and can hold `capacity` keys without reallocating.
+    #[must_use]
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        KeyRwLockBuilder::new()
+            .capacity(capacity)
+            .hasher(hasher)
+            .build()
+    }
+}
 
-        let lock = locks.entry(key).or_default().clone();
-        drop(locks);
+impl<K, V> KeyRwLock<K, V> {
+    /// Return a builder to create a [KeyRwLock] with a custom configuration,
+    /// e.g. to change the [CleanupPolicy].
+    #[must_use]
+    pub fn builder() -> KeyRwLockBuilder<K, V> {
+        KeyRwLockBuilder::new()
+    }
 
-        lock.read_owned().await
+    /// Create new instance of a [KeyRwLock] that creates values using
+    /// `factory`. Unused entries are only removed if `is_removable` returns
+    /// `true` for their value.
+    #[must_use]
+    pub fn with_factory<F, R>(factory: F, is_removable: R) -> Self
+    where
+        F: Fn() -> V + Send + Sync + 'static,
+        R: Fn(&V) -> bool + Send + Sync + 'static,
+    {
+        Self::builder().build_with_factory(factory, is_removable)
     }
+}
 
-    /// Lock this key with exclusive write access, returning a guard. Cleans up
-    /// locks every 1000 accesses.
-    pub async fn write(&self, key: K) -> OwnedRwLockWriteGuard<()> {
-        let mut locks = self.locks.lock().await;
+impl<K, V, S> KeyRwLock<K, V, S>
+where
+    K: Eq + Hash + Send + Clone,
+    S: BuildHasher,
+{
+    /// Lock this key with shared read access, returning a guard.
+    pub async fn read(&self, key: K) -> KeyRwLockReadGuard<K, V, S> {
+        KeyRwLockReadGuard::acquire(self.inner.entry(key), None).await
+    }
 
-        if self.accesses.fetch_add(1, Ordering::Relaxed) % 1000 == 0 {
-            Self::clean_up(&mut locks);
-        }
+    /// Lock this key with exclusive write access, returning a guard.
+    pub async fn write(&self, key: K) -> KeyRwLockWriteGuard<K, V, S> {
+        KeyRwLockWriteGuard::acquire(self.inner.entry(key), None).await
+    }
 
-        let lock = locks.entry(key).or_default().clone();
-        drop(locks);
+    /// Lock this key with upgradable read access, returning a guard. Upgradable
+    /// read access coexists with shared read access, but at most one task can
+    /// hold upgradable read or exclusive write access to a key at a time, so
+    /// the guard can be upgraded to exclusive write access atomically.
+    pub async fn upgradable_read(&self, key: K) -> KeyRwLockUpgradableReadGuard<K, V, S> {
+        KeyRwLockUpgradableReadGuard::acquire(self.inner.entry(key), None).await
+    }
 
-        lock.write_owned().await
+    /// Try lock this key with shared read access, returning immediately.
+    pub async fn try_read(&self, key: K) -> Result<KeyRwLockReadGuard<K, V, S>, TryLockError> {
+        KeyRwLockReadGuard::try_acquire(self.inner.entry(key), None)
     }
 
-    /// Try lock this key with shared read access, returning immediately. Cleans
-    /// up locks every 1000 accesses.
-    pub async fn try_read(&self, key: K) -> Result<OwnedRwLockReadGuard<()>, TryLockError> {
-        let mut locks = self.locks.lock().await;
+    /// Try lock this key with exclusive write access, returning immediately.
+    pub async fn try_write(&self, key: K) -> Result<KeyRwLockWriteGuard<K, V, S>, TryLockError> {
+        KeyRwLockWriteGuard::try_acquire(self.inner.entry(key), None)
+    }
 
-        if self.accesses.fetch_add(1, Ordering::Relaxed) % 1000 == 0 {
-            Self::clean_up(&mut locks);
-        }
+    /// Try lock this key with upgradable read access, returning immediately.
+    pub async fn try_upgradable_read(
+        &self,
+        key: K,
+    ) -> Result<KeyRwLockUpgradableReadGuard<K, V, S>, TryLockError> {
+        KeyRwLockUpgradableReadGuard::try_acquire(self.inner.entry(key), None)
+    }
 
-        let lock = locks.entry(key).or_default().clone();
-        drop(locks);
+    /// Lock this key with shared read access like [`KeyRwLock::read`], taking
+    /// a borrowed form of the key, e.g. a `&str` for a `KeyRwLock<String>`.
+    /// The key is only converted into an owned key if it is not in the map
+    /// yet.
+    pub async fn read_ref<Q>(&self, key: &Q) -> KeyRwLockReadGuard<K, V, S>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        KeyRwLockReadGuard::acquire(self.inner.entry_ref(key), None).await
+    }
 
-        lock.try_read_owned()
+    /// Lock this key with exclusive write access like [`KeyRwLock::write`],
+    /// taking a borrowed form of the key.
+    pub async fn write_ref<Q>(&self, key: &Q) -> KeyRwLockWriteGuard<K, V, S>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        KeyRwLockWriteGuard::acquire(self.inner.entry_ref(key), None).await
     }
 
-    /// Try lock this key with exclusive write access, returning immediately.
-    /// Cleans up locks every 1000 accesses.
-    pub async fn try_write(&self, key: K) -> Result<OwnedRwLockWriteGuard<()>, TryLockError> {
-        let mut locks = self.locks.lock().await;
+    /// Lock this key with upgradable read access like
+    /// [`KeyRwLock::upgradable_read`], taking a borrowed form of the key.
+    pub async fn upgradable_read_ref<Q>(&self, key: &Q) -> KeyRwLockUpgradableReadGuard<K, V, S>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        KeyRwLockUpgradableReadGuard::acquire(self.inner.entry_ref(key), None).await
+    }
 
-        if self.accesses.fetch_add(1, Ordering::Relaxed) % 1000 == 0 {
-            Self::clean_up(&mut locks);
-        }
+    /// Try lock this key with shared read access like [`KeyRwLock::try_read`],
+    /// taking a borrowed form of the key.
+    pub async fn try_read_ref<Q>(
+        &self,
+        key: &Q,
+    ) -> Result<KeyRwLockReadGuard<K, V, S>, TryLockError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        KeyRwLockReadGuard::try_acquire(self.inner.entry_ref(key), None)
+    }
 
-        let lock = locks.entry(key).or_default().clone();
-        drop(locks);
+    /// Try lock this key with exclusive write access like
+    /// [`KeyRwLock::try_write`], taking a borrowed form of the key.
+    pub async fn try_write_ref<Q>(
+        &self,
+        key: &Q,
+    ) -> Result<KeyRwLockWriteGuard<K, V, S>, TryLockError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        KeyRwLockWriteGuard::try_acquire(self.inner.entry_ref(key), None)
+    }
 
-        lock.try_write_owned()
+    /// Try lock this key with upgradable read access like
+    /// [`KeyRwLock::try_upgradable_read`], taking a borrowed form of the key.
+    pub async fn try_upgradable_read_ref<Q>(
+        &self,
+        key: &Q,
+    ) -> Result<KeyRwLockUpgradableReadGuard<K, V, S>, TryLockError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        KeyRwLockUpgradableReadGuard::try_acquire(self.inner.entry_ref(key), None)
     }
 
-    /// Clean up by removing locks that are not locked.
-    pub async fn clean(&self) {
-        let mut locks = self.locks.lock().await;
-        Self::clean_up(&mut locks);
+    /// Lock this key with shared read access like [`KeyRwLock::read`],
+    /// recording `owner` as metadata of the guard for
+    /// [`KeyRwLock::inspect`].
+    #[cfg(feature = "tracking")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tracking")))]
+    pub async fn read_with(&self, key: K, owner: impl Into<String>) -> KeyRwLockReadGuard<K, V, S> {
+        KeyRwLockReadGuard::acquire(self.inner.entry(key), Some(owner.into())).await
+    }
+
+    /// Lock this key with exclusive write access like [`KeyRwLock::write`],
+    /// recording `owner` as metadata of the guard for
+    /// [`KeyRwLock::inspect`].
+    #[cfg(feature = "tracking")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tracking")))]
+    pub async fn write_with(
+        &self,
+        key: K,
+        owner: impl Into<String>,
+    ) -> KeyRwLockWriteGuard<K, V, S> {
+        KeyRwLockWriteGuard::acquire(self.inner.entry(key), Some(owner.into())).await
+    }
+
+    /// Lock this key with upgradable read access like
+    /// [`KeyRwLock::upgradable_read`], recording `owner` as metadata of the
+    /// guard for [`KeyRwLock::inspect`].
+    #[cfg(feature = "tracking")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tracking")))]
+    pub async fn upgradable_read_with(
+        &self,
+        key: K,
+        owner: impl Into<String>,
+    ) -> KeyRwLockUpgradableReadGuard<K, V, S> {
+        KeyRwLockUpgradableReadGuard::acquire(self.inner.entry(key), Some(owner.into())).await
+    }
+
+    /// Try lock this key with shared read access like
+    /// [`KeyRwLock::try_read`], recording `owner` as metadata of the guard for
+    /// [`KeyRwLock::inspect`].
+    #[cfg(feature = "tracking")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tracking")))]
+    pub async fn try_read_with(
+        &self,
+        key: K,
+        owner: impl Into<String>,
+    ) -> Result<KeyRwLockReadGuard<K, V, S>, TryLockError> {
+        KeyRwLockReadGuard::try_acquire(self.inner.entry(key), Some(owner.into()))
+    }
+
+    /// Try lock this key with exclusive write access like
+    /// [`KeyRwLock::try_write`], recording `owner` as metadata of the guard
+    /// for [`KeyRwLock::inspect`].
+    #[cfg(feature = "tracking")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tracking")))]
+    pub async fn try_write_with(
+        &self,
+        key: K,
+        owner: impl Into<String>,
+    ) -> Result<KeyRwLockWriteGuard<K, V, S>, TryLockError> {
+        KeyRwLockWriteGuard::try_acquire(self.inner.entry(key), Some(owner.into()))
+    }
+
+    /// Try lock this key with upgradable read access like
+    /// [`KeyRwLock::try_upgradable_read`], recording `owner` as metadata of
+    /// the guard for [`KeyRwLock::inspect`].
+    #[cfg(feature = "tracking")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tracking")))]
+    pub async fn try_upgradable_read_with(
+        &self,
+        key: K,
+        owner: impl Into<String>,
+    ) -> Result<KeyRwLockUpgradableReadGuard<K, V, S>, TryLockError> {
+        KeyRwLockUpgradableReadGuard::try_acquire(self.inner.entry(key), Some(owner.into()))
+    }
+
+    /// Return the guards currently holding this key and the tasks waiting for
+    /// it, e.g. to find out who holds a key that seems to be stuck.
+    #[cfg(feature = "tracking")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tracking")))]
+    #[must_use]
+    pub fn inspect(&self, key: &K) -> LockInfo {
+        self.inner
+            .with_lock(key, |lock| lock.tracker().info())
+            .unwrap_or_default()
     }
 
-    /// Remove locks that are not locked currently.
-    fn clean_up(locks: &mut HashMap<K, Arc<RwLock<()>>>) {
-        let mut to_remove = Vec::new();
-        for (key, lock) in locks.iter() {
-            if lock.try_write().is_ok() {
-                to_remove.push(key.clone());
+    /// Return the holders and waiters of every key that is currently held or
+    /// waited for. The keys are collected one shard at a time, so the result
+    /// may be slightly inconsistent while other tasks lock keys concurrently.
+    #[cfg(feature = "tracking")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tracking")))]
+    #[must_use]
+    pub fn inspect_all(&self) -> Vec<(K, LockInfo)> {
+        let mut keys = Vec::new();
+        self.inner.for_each(|key, lock| {
+            let info = lock.tracker().info();
+            if !info.is_empty() {
+                keys.push((key.clone(), info));
             }
+        });
+        keys
+    }
+
+    /// Clean up by removing locks that are neither locked nor waited on.
+    ///
+    /// This is usually not necessary, as entries are removed automatically
+    /// when they are released. It is only needed if values can become
+    /// removable while they are not locked, e.g. through interior mutability.
+    pub async fn clean(&self) {
+        self.inner.clean_up();
+    }
+
+    /// Return a snapshot of the statistics of this lock.
+    ///
+    /// The counts of keys are collected one shard at a time, so they may be
+    /// slightly inconsistent while other tasks lock keys concurrently.
+    #[cfg(feature = "stats")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "stats")))]
+    #[must_use]
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.inner.metrics().snapshot();
+        self.inner.for_each(|_, lock| {
+            stats.keys += 1;
+            let (readers, writers) = lock.holders();
+            stats.read_locked_keys += usize::from(readers > 0);
+            stats.write_locked_keys += usize::from(writers > 0);
+        });
+        stats
+    }
+}
+
+#[cfg(feature = "time")]
+#[cfg_attr(docsrs, doc(cfg(feature = "time")))]
+impl<K, V, S> KeyRwLock<K, V, S>
+where
+    K: Eq + Hash + Send + Clone,
+    S: BuildHasher,
+{
+    /// Lock this key with shared read access, giving up if the lock could not
+    /// be acquired within `timeout`.
+    pub async fn read_timeout(
+        &self,
+        key: K,
+        timeout: Duration,
+    ) -> Result<KeyRwLockReadGuard<K, V, S>, TimeoutError<K>> {
+        self.read_timeout_at(key, Instant::now() + timeout).await
+    }
+
+    /// Lock this key with exclusive write access, giving up if the lock could
+    /// not be acquired within `timeout`.
+    pub async fn write_timeout(
+        &self,
+        key: K,
+        timeout: Duration,
+    ) -> Result<KeyRwLockWriteGuard<K, V, S>, TimeoutError<K>> {
+        self.write_timeout_at(key, Instant::now() + timeout).await
+    }
+
+    /// Lock this key with upgradable read access, giving up if the lock could
+    /// not be acquired within `timeout`.
+    pub async fn upgradable_read_timeout(
+        &self,
+        key: K,
+        timeout: Duration,
+    ) -> Result<KeyRwLockUpgradableReadGuard<K, V, S>, TimeoutError<K>> {
+        self.upgradable_read_timeout_at(key, Instant::now() + timeout)
+            .await
+    }
+
+    /// Lock this key with shared read access, giving up if the lock could not
+    /// be acquired before `deadline`.
+    pub async fn read_timeout_at(
+        &self,
+        key: K,
+        deadline: Instant,
+    ) -> Result<KeyRwLockReadGuard<K, V, S>, TimeoutError<K>> {
+        Self::acquire_until(deadline, key.clone(), self.read(key)).await
+    }
+
+    /// Lock this key with exclusive write access, giving up if the lock could
+    /// not be acquired before `deadline`.
+    pub async fn write_timeout_at(
+        &self,
+        key: K,
+        deadline: Instant,
+    ) -> Result<KeyRwLockWriteGuard<K, V, S>, TimeoutError<K>> {
+        Self::acquire_until(deadline, key.clone(), self.write(key)).await
+    }
+
+    /// Lock this key with upgradable read access, giving up if the lock could
+    /// not be acquired before `deadline`.
+    pub async fn upgradable_read_timeout_at(
+        &self,
+        key: K,
+        deadline: Instant,
+    ) -> Result<KeyRwLockUpgradableReadGuard<K, V, S>, TimeoutError<K>> {
+        Self::acquire_until(deadline, key.clone(), self.upgradable_read(key)).await
+    }
+
+    /// Wait for this acquisition until the deadline. If it times out, the
+    /// pending acquisition is dropped, which releases its reference to the
+    /// entry.
+    async fn acquire_until<G>(
+        deadline: Instant,
+        key: K,
+        acquire: impl Future<Output = G>,
+    ) -> Result<G, TimeoutError<K>> {
+        let start = Instant::now();
+        tokio::time::timeout_at(deadline, acquire)
+            .await
+            .map_err(|_| TimeoutError::new(key, start.elapsed()))
+    }
+}
+
+impl<K, V, S> KeyRwLock<K, V, S>
+where
+    K: Eq + Hash + Ord + Send + Clone,
+    S: BuildHasher,
+{
+    /// Lock all of these keys with shared read access, returning a single
+    /// guard. See [`KeyRwLock::lock_set`].
+    pub async fn read_many<I>(&self, keys: I) -> KeyRwLockSetGuard<K, V, S>
+    where
+        I: IntoIterator<Item = K>,
+    {
+        self.lock_set(keys.into_iter().map(|key| (key, LockMode::Read)))
+            .await
+    }
+
+    /// Lock all of these keys with exclusive write access, returning a single
+    /// guard. See [`KeyRwLock::lock_set`].
+    pub async fn write_many<I>(&self, keys: I) -> KeyRwLockSetGuard<K, V, S>
+    where
+        I: IntoIterator<Item = K>,
+    {
+        self.lock_set(keys.into_iter().map(|key| (key, LockMode::Write)))
+            .await
+    }
+
+    /// Lock all of these keys with the given access, returning a single guard.
+    ///
+    /// The keys are locked in ascending order, so concurrent calls with
+    /// overlapping keys can not deadlock each other. If a key is given more
+    /// than once, it is locked only once, with write access if any of its
+    /// occurrences requests write access.
+    pub async fn lock_set<I>(&self, keys: I) -> KeyRwLockSetGuard<K, V, S>
+    where
+        I: IntoIterator<Item = (K, LockMode)>,
+    {
+        let mut guards = Vec::new();
+        for (key, mode) in Self::canonical_order(keys) {
+            guards.push(match mode {
+                LockMode::Read => KeyRwLockGuard::Read(self.read(key).await),
+                LockMode::Write => KeyRwLockGuard::Write(self.write(key).await),
+            });
+        }
+        KeyRwLockSetGuard::new(guards)
+    }
+
+    /// Try lock all of these keys with the given access, returning
+    /// immediately. Either all keys are locked or none of them is. See
+    /// [`KeyRwLock::lock_set`].
+    pub async fn try_lock_set<I>(&self, keys: I) -> Result<KeyRwLockSetGuard<K, V, S>, TryLockError>
+    where
+        I: IntoIterator<Item = (K, LockMode)>,
+    {
+        let mut guards = Vec::new();
+        for (key, mode) in Self::canonical_order(keys) {
+            guards.push(match mode {
+                LockMode::Read => KeyRwLockGuard::Read(self.try_read(key).await?),
+                LockMode::Write => KeyRwLockGuard::Write(self.try_write(key).await?),
+            });
         }
-        for key in to_remove {
-            locks.remove(&key);
+        Ok(KeyRwLockSetGuard::new(guards))
+    }
+
+    /// Sort and deduplicate these keys, preferring write access for duplicates.
+    fn canonical_order<I>(keys: I) -> BTreeMap<K, LockMode>
+    where
+        I: IntoIterator<Item = (K, LockMode)>,
+    {
+        let mut ordered = BTreeMap::new();
+        for (key, mode) in keys {
+            let entry = ordered.entry(key).or_insert(mode);
+            *entry = (*entry).max(mode);
         }
+        ordered
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
     use super::*;
 
     #[tokio::test]
@@ -141,6 +639,74 @@ mod tests {
         assert!(lock.try_write("bar").await.is_err());
     }
 
+    #[tokio::test]
+    async fn test_borrowed_keys() {
+        let lock = KeyRwLock::<String>::new();
+
+        let foo = lock.write_ref("foo").await;
+        assert_eq!(foo.key(), "foo");
+        assert!(lock.try_read_ref("foo").await.is_err());
+        assert!(lock.try_write("foo".to_owned()).await.is_err());
+        drop(foo);
+
+        let foo = lock.read_ref("foo").await;
+        let foo2 = lock.try_upgradable_read_ref("foo").await.unwrap();
+        // the key is only allocated once, when the entry is created
+        assert!(std::ptr::eq(foo.key(), foo2.key()));
+        drop((foo, foo2));
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_custom_hasher() {
+        use std::hash::{BuildHasherDefault, Hasher};
+
+        /// Uses `u64` keys as their own hash.
+        #[derive(Default)]
+        struct IdentityHasher(u64);
+
+        impl Hasher for IdentityHasher {
+            fn finish(&self) -> u64 {
+                self.0
+            }
+
+            fn write(&mut self, _bytes: &[u8]) {
+                unreachable!("only u64 keys are hashed");
+            }
+
+            fn write_u64(&mut self, value: u64) {
+                self.0 = value;
+            }
+        }
+
+        type Identity = BuildHasherDefault<IdentityHasher>;
+
+        let lock =
+            KeyRwLock::<u64, (), Identity>::with_capacity_and_hasher(64, Identity::default());
+        let mut guards = Vec::new();
+        for key in 0..64 {
+            guards.push(lock.write(key).await);
+        }
+        assert_eq!(lock.inner.len(), 64);
+        assert!(lock.try_read(7).await.is_err());
+        drop(guards);
+        assert_eq!(lock.inner.len(), 0);
+
+        // sequential keys are spread over the shards
+        let lock = KeyRwLock::<u64>::builder()
+            .shards(4)
+            .hasher(Identity::default())
+            .build();
+        let mut guards = Vec::new();
+        for key in 0..64 {
+            guards.push(lock.read(key).await);
+        }
+        for shard in lock.inner.shards() {
+            assert!(shard.lock().len() > 4);
+        }
+        drop(guards);
+    }
+
     #[tokio::test]
     async fn test_clean_up() {
         let lock = KeyRwLock::new();
@@ -148,10 +714,647 @@ mod tests {
         let _bar_write = lock.write("bar_write").await;
         let _foo_read = lock.read("foo_read").await;
         let _bar_read = lock.read("bar_read").await;
-        assert_eq!(lock.locks.lock().await.len(), 4);
+        assert_eq!(lock.inner.len(), 4);
         drop(_foo_read);
         drop(_bar_write);
+        assert_eq!(lock.inner.len(), 2);
+        lock.clean().await;
+        assert_eq!(lock.inner.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_clean_up_interior_mutability() {
+        let lock = KeyRwLock::with_factory(Arc::<AtomicUsize>::default, |value| {
+            value.load(Ordering::Relaxed) == 0
+        });
+
+        let guard = lock.read("foo").await;
+        let foo = Arc::clone(&guard);
+        foo.store(1, Ordering::Relaxed);
+        drop(guard);
+        lock.clean().await;
+        assert_eq!(lock.inner.len(), 1);
+
+        foo.store(0, Ordering::Relaxed);
+        lock.clean().await;
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_remove_on_release() {
+        let lock = KeyRwLock::new();
+
+        let foo1 = lock.read("foo").await;
+        let foo2 = lock.read("foo").await;
+        assert_eq!(foo1.key(), &"foo");
+        drop(foo1);
+        assert_eq!(lock.inner.len(), 1);
+        drop(foo2);
+        assert_eq!(lock.inner.len(), 0);
+
+        let _foo = lock.write("foo").await;
+        assert!(lock.try_write("bar").await.is_ok());
+        assert!(lock.try_read("foo").await.is_err());
+        assert_eq!(lock.inner.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_remove_cancelled_acquirer() {
+        let lock = KeyRwLock::new();
+
+        let foo = lock.write("foo").await;
+        let pending = tokio::time::timeout(Duration::from_millis(10), lock.read("foo")).await;
+        assert!(pending.is_err());
+        assert_eq!(lock.inner.len(), 1);
+
+        drop(foo);
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_values() {
+        let lock = KeyRwLock::<_, Vec<i32>>::default();
+
+        lock.write("foo").await.push(42);
+        assert_eq!(*lock.read("foo").await, [42]);
+        assert!(lock.read("bar").await.is_empty());
+        assert_eq!(lock.inner.len(), 1);
+
+        lock.write("foo").await.clear();
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_factory() {
+        let lock = KeyRwLock::with_factory(|| 7, |value| *value < 0);
+
+        assert_eq!(*lock.read("foo").await, 7);
+        *lock.write("bar").await = -1;
+        assert_eq!(lock.inner.len(), 1);
+        assert_eq!(*lock.read("foo").await, 7);
+    }
+
+    #[tokio::test]
+    async fn test_clean_up_keeps_pending_acquirers() {
+        let lock = KeyRwLock::new();
+
+        let pending = lock.inner.entry("foo");
+        lock.clean().await;
+        let _foo = KeyRwLockWriteGuard::acquire(pending, None).await;
+
+        assert!(lock.try_write("foo").await.is_err());
+        assert!(lock.try_read("foo").await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_clean_up_race() {
+        let lock = Arc::new(KeyRwLock::new());
+        let holders = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..16)
+            .map(|_| {
+                let lock = Arc::clone(&lock);
+                let holders = Arc::clone(&holders);
+                tokio::spawn(async move {
+                    for _ in 0..500 {
+                        // widen the window between looking up the lock and
+                        // acquiring it, which is where clean up used to strike
+                        let pending = lock.inner.entry("foo");
+                        tokio::task::yield_now().await;
+                        let _guard = KeyRwLockWriteGuard::acquire(pending, None).await;
+                        assert_eq!(holders.fetch_add(1, Ordering::SeqCst), 0);
+                        tokio::task::yield_now().await;
+                        holders.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let cleaner = {
+            let lock = Arc::clone(&lock);
+            tokio::spawn(async move {
+                loop {
+                    lock.clean().await;
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+        cleaner.abort();
+    }
+
+    #[tokio::test]
+    async fn test_lock_set() {
+        let lock = KeyRwLock::<_, i32>::default();
+
+        let mut guard = lock
+            .lock_set([
+                ("foo", LockMode::Write),
+                ("bar", LockMode::Read),
+                ("baz", LockMode::Read),
+                ("baz", LockMode::Write),
+            ])
+            .await;
+        assert_eq!(guard.keys().collect::<Vec<_>>(), [&"bar", &"baz", &"foo"]);
+        *guard.get_mut(&"foo").unwrap() = 1;
+        assert!(guard.get_mut(&"bar").is_none());
+        assert_eq!(guard.get(&"bar"), Some(&0));
+        assert_eq!(guard.get(&"qux"), None);
+
+        assert!(lock.try_read("bar").await.is_ok());
+        assert!(lock.try_read("baz").await.is_err());
+        assert!(lock.try_read("foo").await.is_err());
+        assert!(lock
+            .try_lock_set([("bar", LockMode::Read), ("qux", LockMode::Write)])
+            .await
+            .is_ok());
+        assert!(lock
+            .try_lock_set([("qux", LockMode::Write), ("foo", LockMode::Read)])
+            .await
+            .is_err());
+        assert!(lock.try_write("qux").await.is_ok());
+
+        drop(guard);
+        assert_eq!(
+            *lock.read_many(["foo", "bar"]).await.get(&"foo").unwrap(),
+            1
+        );
+        assert_eq!(lock.inner.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_write_many_no_deadlock() {
+        let lock = Arc::new(KeyRwLock::<_, i32>::default());
+
+        let tasks = (0..8)
+            .map(|i| {
+                let lock = Arc::clone(&lock);
+                let keys = if i % 2 == 0 { ["a", "b"] } else { ["b", "a"] };
+                tokio::spawn(async move {
+                    for _ in 0..200 {
+                        let mut guard = lock.write_many(keys).await;
+                        *guard.get_mut(&"a").unwrap() += 1;
+                        tokio::task::yield_now().await;
+                        *guard.get_mut(&"b").unwrap() -= 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let tasks = async {
+            for task in tasks {
+                task.await.unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), tasks)
+            .await
+            .unwrap();
+        assert_eq!(*lock.read("a").await, 1600);
+        assert_eq!(*lock.read("b").await, -1600);
+    }
+
+    #[tokio::test]
+    async fn test_shards() {
+        let lock = KeyRwLock::<_, usize>::with_shards(4);
+        assert_eq!(lock.inner.shards().len(), 4);
+
+        let mut guards = Vec::new();
+        for key in 0..100 {
+            guards.push(lock.write(key).await);
+        }
+        assert_eq!(lock.inner.len(), 100);
+        assert!(lock
+            .inner
+            .shards()
+            .iter()
+            .all(|shard| shard.lock().len() > 0));
+        for key in 0..100 {
+            assert!(lock.try_read(key).await.is_err());
+        }
+
+        drop(guards);
+        assert_eq!(lock.inner.len(), 0);
+
+        let lock = KeyRwLock::<_, usize>::with_shards(1);
+        *lock.write("foo").await = 1;
+        *lock.write("bar").await = 2;
+        assert_eq!(lock.inner.len(), 2);
+        assert_eq!(*lock.read("foo").await, 1);
+    }
+
+    #[test]
+    #[should_panic = "number of shards must not be zero"]
+    fn test_zero_shards() {
+        let _ = KeyRwLock::<()>::with_shards(0);
+    }
+
+    #[tokio::test]
+    async fn test_downgrade() {
+        let lock = KeyRwLock::<_, i32>::default();
+
+        let mut foo = lock.write("foo").await;
+        *foo = 42;
+        let foo = foo.downgrade();
+        assert_eq!(*foo, 42);
+        assert_eq!(*lock.try_read("foo").await.unwrap(), 42);
+        assert!(lock.try_write("foo").await.is_err());
+        assert!(lock.try_upgradable_read("foo").await.is_ok());
+        drop(foo);
+
+        let foo = lock.write("foo").await.downgrade_to_upgradable();
+        assert!(lock.try_read("foo").await.is_ok());
+        assert!(lock.try_upgradable_read("foo").await.is_err());
+        let foo = foo.downgrade();
+        assert!(lock.try_upgradable_read("foo").await.is_ok());
+        drop(foo);
+
+        assert!(lock.try_write("foo").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_upgradable_read() {
+        let lock = KeyRwLock::<_, i32>::default();
+
+        let foo = lock.upgradable_read("foo").await;
+        assert_eq!(foo.key(), &"foo");
+        let reader = lock.read("foo").await;
+        assert!(lock.try_upgradable_read("foo").await.is_err());
+        assert!(lock.try_write("foo").await.is_err());
+
+        let upgrade = tokio::spawn(async move {
+            let mut foo = foo.upgrade().await;
+            *foo += 1;
+        });
+        tokio::task::yield_now().await;
+        assert!(!upgrade.is_finished());
+        assert!(lock.try_write("foo").await.is_err());
+
+        drop(reader);
+        upgrade.await.unwrap();
+        assert_eq!(*lock.read("foo").await, 1);
+        assert_eq!(lock.inner.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_upgrade_excludes_writers() {
+        let lock = Arc::new(KeyRwLock::<_, i32>::default());
+
+        let tasks = (0..8)
+            .map(|i| {
+                let lock = Arc::clone(&lock);
+                tokio::spawn(async move {
+                    for _ in 0..200 {
+                        if i % 2 == 0 {
+                            let guard = lock.upgradable_read("foo").await;
+                            let value = *guard;
+                            tokio::task::yield_now().await;
+                            let mut guard = guard.upgrade().await;
+                            assert_eq!(*guard, value);
+                            *guard += 1;
+                        } else {
+                            *lock.write("foo").await += 1;
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(*lock.read("foo").await, 1600);
+    }
+
+    /// Hold a key in the `held` mode, let a task wait for each of the
+    /// `waiting` modes in order and return the order in which the tasks
+    /// acquire the key.
+    async fn acquisition_order(
+        fairness: FairnessPolicy,
+        held: LockMode,
+        waiting: [LockMode; 2],
+    ) -> Vec<usize> {
+        let lock = Arc::new(KeyRwLock::<_>::builder().fairness(fairness).build());
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let guard = lock.lock_set([("foo", held)]).await;
+        let tasks = waiting
+            .into_iter()
+            .enumerate()
+            .map(|(idx, mode)| {
+                let lock = Arc::clone(&lock);
+                let order = Arc::clone(&order);
+                tokio::spawn(async move {
+                    let _guard = lock.lock_set([("foo", mode)]).await;
+                    order.lock().unwrap().push(idx);
+                    tokio::task::yield_now().await;
+                })
+            })
+            .collect::<Vec<_>>();
+        tokio::task::yield_now().await;
+
+        drop(guard);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let order = order.lock().unwrap().clone();
+        order
+    }
+
+    #[tokio::test]
+    async fn test_fairness_fifo() {
+        use LockMode::{Read, Write};
+
+        let order = acquisition_order(FairnessPolicy::Fifo, Write, [Read, Write]).await;
+        assert_eq!(order, [0, 1]);
+        let order = acquisition_order(FairnessPolicy::Fifo, Read, [Write, Read]).await;
+        assert_eq!(order, [0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_fairness_writer_preferring() {
+        use LockMode::{Read, Write};
+
+        let order = acquisition_order(FairnessPolicy::WriterPreferring, Write, [Read, Write]).await;
+        assert_eq!(order, [1, 0]);
+        let order = acquisition_order(FairnessPolicy::WriterPreferring, Read, [Write, Read]).await;
+        assert_eq!(order, [0, 1]);
+
+        let lock = KeyRwLock::<_>::builder()
+            .fairness(FairnessPolicy::WriterPreferring)
+            .build();
+        let foo = lock.write("foo").await;
+        assert!(lock.try_read("foo").await.is_err());
+        let foo = foo.downgrade();
+        assert!(lock.try_read("foo").await.is_ok());
+        drop(foo);
+        assert!(lock.try_write("foo").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_fairness_reader_preferring() {
+        use LockMode::{Read, Write};
+
+        let order = acquisition_order(FairnessPolicy::ReaderPreferring, Read, [Write, Read]).await;
+        assert_eq!(order, [1, 0]);
+        let order = acquisition_order(FairnessPolicy::ReaderPreferring, Write, [Read, Write]).await;
+        assert_eq!(order, [0, 1]);
+        // waiting readers are served first, even if the writer waited longer
+        let order = acquisition_order(FairnessPolicy::ReaderPreferring, Write, [Write, Read]).await;
+        assert_eq!(order, [1, 0]);
+
+        let lock = KeyRwLock::<_>::builder()
+            .fairness(FairnessPolicy::ReaderPreferring)
+            .build();
+        let foo = lock.upgradable_read("foo").await;
+        let reader = lock.read("foo").await;
+        let upgrade = tokio::spawn(foo.upgrade());
+        tokio::task::yield_now().await;
+        drop(reader);
+        let foo = upgrade.await.unwrap();
+        assert!(lock.try_read("foo").await.is_err());
+        let foo = foo.downgrade();
+        assert!(lock.try_write("foo").await.is_err());
+        drop(foo);
+        assert!(lock.try_write("foo").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_interval() {
+        let lock = KeyRwLock::<_>::builder()
+            .cleanup(CleanupPolicy::Interval(3))
+            .build();
+
+        for key in 0..3 {
+            drop(lock.read(key).await);
+        }
+        assert_eq!(lock.inner.len(), 3);
+
+        let _foo = lock.write(3).await;
+        assert_eq!(lock.inner.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_incremental() {
+        let lock = KeyRwLock::<_>::builder()
+            .shards(1)
+            .cleanup(CleanupPolicy::Incremental(1))
+            .build();
+
+        let foo = lock.read("foo").await;
+        drop(lock.read("bar").await);
+        drop(lock.read("baz").await);
+        assert_eq!(lock.inner.len(), 2);
+        drop(foo);
+        assert_eq!(lock.inner.len(), 2);
+
+        let _qux = lock.read("qux").await;
+        assert_eq!(lock.inner.len(), 2);
+        let _quux = lock.read("quux").await;
+        assert_eq!(lock.inner.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_incremental_after_clean() {
+        let lock = KeyRwLock::<_>::builder()
+            .shards(1)
+            .cleanup(CleanupPolicy::Incremental(1))
+            .cleanup_threshold(8)
+            .build();
+
+        drop(lock.read("foo").await);
+        assert_eq!(lock.inner.shards()[0].lock().queued(), 1);
+        lock.clean().await;
+        assert_eq!(lock.inner.len(), 0);
+        assert_eq!(lock.inner.shards()[0].lock().queued(), 0);
+
+        drop(lock.read("foo").await);
+        assert_eq!(lock.inner.shards()[0].lock().queued(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_threshold() {
+        let lock = KeyRwLock::<_>::builder().cleanup_threshold(2).build();
+
+        let guards = lock.write_many(["foo", "bar", "baz"]).await;
+        assert_eq!(lock.inner.len(), 3);
+        drop(guards);
+        assert_eq!(lock.inner.len(), 2);
+
+        lock.clean().await;
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_disabled() {
+        let lock = KeyRwLock::<_>::builder()
+            .cleanup(CleanupPolicy::Disabled)
+            .capacity(16)
+            .build();
+
+        for key in 0..8 {
+            drop(lock.write(key).await);
+        }
+        assert_eq!(lock.inner.len(), 8);
+
+        let _foo = lock.read(0).await;
+        lock.clean().await;
+        assert_eq!(lock.inner.len(), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn test_grace_period() {
+        let lock = KeyRwLock::<_>::builder()
+            .grace_period(Duration::from_millis(50))
+            .build();
+
+        drop(lock.write("foo").await);
+        lock.clean().await;
+        assert_eq!(lock.inner.len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        drop(lock.read("bar").await);
+        lock.clean().await;
+        assert_eq!(lock.inner.len(), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn test_grace_period_on_release() {
+        let lock = KeyRwLock::<_>::builder()
+            .shards(1)
+            .grace_period(Duration::from_millis(50))
+            .build();
+
+        drop(lock.write("foo").await);
+        assert_eq!(lock.inner.len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        drop(lock.read("bar").await);
+        assert_eq!(lock.inner.len(), 1);
+    }
+
+    #[cfg(feature = "reaper")]
+    #[tokio::test]
+    async fn test_reaper() {
+        let lock = Arc::new(
+            KeyRwLock::<_>::builder()
+                .cleanup(CleanupPolicy::Disabled)
+                .build(),
+        );
+        let reaper = lock.spawn_reaper(Duration::from_millis(10));
+
+        let foo = lock.write("foo").await;
+        drop(lock.read("bar").await);
+        assert_eq!(lock.inner.len(), 2);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(lock.inner.len(), 1);
+        assert!(!reaper.is_finished());
+
+        drop(foo);
+        drop(lock);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(reaper.is_finished());
+    }
+
+    #[cfg(feature = "reaper")]
+    #[tokio::test]
+    async fn test_reaper_stop() {
+        let lock = Arc::new(
+            KeyRwLock::<_>::builder()
+                .cleanup(CleanupPolicy::Disabled)
+                .build(),
+        );
+        lock.spawn_reaper(Duration::from_millis(10)).stop();
+
+        drop(lock.read("foo").await);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(lock.inner.len(), 1);
+    }
+
+    #[cfg(feature = "time")]
+    #[tokio::test]
+    async fn test_timeout() {
+        let lock = KeyRwLock::<_, i32>::default();
+
+        let foo = lock.write("foo").await;
+        let err = lock
+            .read_timeout("foo", Duration::from_millis(20))
+            .await
+            .unwrap_err();
+        assert_eq!(err.key(), &"foo");
+        assert!(err.elapsed() >= Duration::from_millis(20));
+        assert!(lock
+            .write_timeout_at("foo", Instant::now() + Duration::from_millis(10))
+            .await
+            .is_err());
+        assert!(lock
+            .upgradable_read_timeout("foo", Duration::from_millis(10))
+            .await
+            .is_err());
+        assert!(lock
+            .write_timeout("bar", Duration::from_millis(10))
+            .await
+            .is_ok());
+        assert_eq!(lock.inner.len(), 1);
+
+        drop(foo);
+        assert_eq!(lock.inner.len(), 0);
+
+        let foo = lock.read("foo").await;
+        let (reader, writer) = tokio::join!(
+            lock.read_timeout("foo", Duration::from_secs(10)),
+            lock.write_timeout("foo", Duration::from_millis(10)),
+        );
+        assert!(reader.is_ok());
+        assert_eq!(writer.unwrap_err().into_key(), "foo");
+        drop((foo, reader));
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[cfg(feature = "stats")]
+    #[tokio::test]
+    async fn test_stats() {
+        let lock = KeyRwLock::new();
+
+        let foo = lock.write("foo").await;
+        let bar = lock.read("bar").await;
+        let baz = lock.upgradable_read("baz").await;
+        let stats = lock.stats();
+        assert_eq!(stats.keys, 3);
+        assert_eq!(stats.read_locked_keys, 2);
+        assert_eq!(stats.write_locked_keys, 1);
+        assert_eq!(stats.fast_acquisitions, 3);
+        assert_eq!(stats.contended_acquisitions, 0);
+
+        let (foo2, ()) = tokio::join!(lock.read("foo"), async {
+            tokio::task::yield_now().await;
+            drop(foo);
+        });
+        let mut baz = baz.upgrade().await;
+        *baz = ();
+        let stats = lock.stats();
+        assert_eq!(stats.read_locked_keys, 2);
+        assert_eq!(stats.write_locked_keys, 1);
+        assert_eq!(stats.fast_acquisitions, 3);
+        assert_eq!(stats.contended_acquisitions, 1);
+        assert_eq!(stats.wait_times.count(), 1);
+
+        let baz = baz.downgrade();
+        assert!(lock.try_write("bar").await.is_err());
+        let stats = lock.stats();
+        assert_eq!(stats.read_locked_keys, 3);
+        assert_eq!(stats.write_locked_keys, 0);
+        assert_eq!(stats.removed_entries, 0);
+
+        drop((foo2, bar, baz));
         lock.clean().await;
-        assert_eq!(lock.locks.lock().await.len(), 2);
+        let stats = lock.stats();
+        assert_eq!(stats.keys, 0);
+        assert_eq!(stats.removed_entries, 3);
+        assert_eq!(stats.clean_ups, 1);
     }
 }
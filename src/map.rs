@@ -0,0 +1,671 @@
+use alloc::{
+    borrow::{Cow, ToOwned},
+    boxed::Box,
+    collections::VecDeque,
+    sync::Arc,
+};
+use core::{
+    borrow::Borrow,
+    fmt,
+    hash::{BuildHasher, Hash, Hasher},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+#[cfg(feature = "deadlock-detection")]
+use crate::deadlock::Detector;
+#[cfg(feature = "tracing")]
+use crate::trace::KeyFormatter;
+use crate::{
+    compat::{self, HashMap, Instant, Mutex, MutexGuard, RandomState},
+    fairness::FairnessPolicy,
+    stats::Metrics,
+    trace::CleanUp,
+};
+
+/// A lock that protects a value and can be stored in a [LockMap].
+pub(crate) trait Lock {
+    /// The value protected by the lock.
+    type Value;
+
+    /// Create a new unlocked lock for this value.
+    fn new(value: Self::Value) -> Self;
+
+    /// Return a mutable reference to the value, which is possible without
+    /// locking as the lock is borrowed mutably.
+    fn get_mut(&mut self) -> &mut Self::Value;
+}
+
+/// Decides when unused entries are removed from the map automatically.
+///
+/// Entries are only ever removed if they are neither locked nor waited on and
+/// their value is removable. Regardless of the policy, unused entries can be
+/// removed manually using [`KeyRwLock::clean`](crate::KeyRwLock::clean).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CleanupPolicy {
+    /// Remove an entry as soon as its last guard or pending acquirer goes
+    /// away. This is the default.
+    OnRelease,
+    /// Scan the whole map for unused entries every `n` accesses, locking one
+    /// shard at a time.
+    Interval(usize),
+    /// Check up to `n` released entries on every access, spreading the cost of
+    /// clean up evenly over all accesses.
+    Incremental(usize),
+    /// Never remove entries automatically.
+    Disabled,
+}
+
+/// Configuration of a [LockMap].
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    /// Number of shards of the map.
+    pub(crate) shards: usize,
+    /// Number of entries the map can hold without reallocating.
+    pub(crate) capacity: usize,
+    /// When unused entries are removed automatically.
+    pub(crate) cleanup: CleanupPolicy,
+    /// Entries are only removed automatically while the map holds more than
+    /// this number of entries.
+    pub(crate) threshold: usize,
+    /// How long an entry must have been unused before it can be removed.
+    pub(crate) grace_period: Option<Duration>,
+    /// In which order waiting readers and writers of a key are served. Only
+    /// used by the async locks.
+    pub(crate) fairness: FairnessPolicy,
+    /// Whether a backtrace is captured for every tracked acquisition. Only
+    /// used by the async locks.
+    #[cfg(feature = "backtrace")]
+    pub(crate) capture_backtraces: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            shards: default_shards(),
+            capacity: 0,
+            cleanup: CleanupPolicy::OnRelease,
+            threshold: 0,
+            grace_period: None,
+            fairness: FairnessPolicy::default(),
+            #[cfg(feature = "backtrace")]
+            capture_backtraces: false,
+        }
+    }
+}
+
+/// An entry of the map.
+struct Slot<L> {
+    /// The lock of the entry.
+    lock: Arc<L>,
+    /// When the entry was released last. Only updated if a grace period is
+    /// configured.
+    released: Instant,
+    /// Whether the key is in the queue of released keys of its shard.
+    queued: bool,
+}
+
+/// A shard of the map.
+pub(crate) struct Shard<K, L, S = RandomState> {
+    /// The locks of the keys in this shard. Keys are shared with all
+    /// references to their entry, so they are only allocated on insertion.
+    locks: HashMap<Arc<K>, Slot<L>, S>,
+    /// Keys that have been released and may be unused. Each key is queued at
+    /// most once. Only used for [`CleanupPolicy::Incremental`].
+    released: VecDeque<Arc<K>>,
+}
+
+impl<K, L, S> Shard<K, L, S> {
+    /// Return the number of entries in this shard.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.locks.len()
+    }
+
+    /// Return the number of keys in the queue of released keys.
+    #[cfg(test)]
+    pub(crate) fn queued(&self) -> usize {
+        self.released.len()
+    }
+}
+
+impl<K, L, S> fmt::Debug for Shard<K, L, S>
+where
+    K: fmt::Debug,
+    L: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.locks.iter().map(|(key, slot)| (key, &slot.lock)))
+            .finish()
+    }
+}
+
+/// Creates the value for a new entry with this key.
+type Factory<K, V> = Box<dyn Fn(&K) -> V + Send + Sync>;
+
+/// Decides whether an unused entry with this value may be removed.
+type IsRemovable<V> = Box<dyn Fn(&V) -> bool + Send + Sync>;
+
+/// A map of locks for specific keys, which is shared with all guards. Entries
+/// are created on demand and removed according to the [CleanupPolicy] once
+/// they are unused.
+///
+/// The map is split into shards that are locked independently, so operations
+/// on keys in different shards never contend with each other. Keys are hashed
+/// using `S`, both to select their shard and within the shard.
+pub(crate) struct LockMap<K, L: Lock, S = RandomState> {
+    /// The shards of the map, selected by the hash of the key.
+    shards: Box<[Mutex<Shard<K, L, S>>]>,
+    /// Hashes keys to select their shard.
+    hasher: S,
+    /// Creates the value for keys that are not in the map yet.
+    factory: Factory<K, L::Value>,
+    /// Decides whether an unused entry may be removed.
+    is_removable: IsRemovable<L::Value>,
+    /// The configuration of the map.
+    config: Config,
+    /// Number of entries in all shards.
+    len: AtomicUsize,
+    /// Number of accesses, used to trigger [`CleanupPolicy::Interval`].
+    accesses: AtomicUsize,
+    /// Statistics of the map and its entries.
+    metrics: Metrics,
+    /// Detects deadlocks between the holders of the keys, if enabled.
+    #[cfg(feature = "deadlock-detection")]
+    detector: Option<Arc<Detector<K>>>,
+    /// Formats keys for tracing, if they are recorded.
+    #[cfg(feature = "tracing")]
+    key_formatter: Option<KeyFormatter<K>>,
+}
+
+impl<K, L> LockMap<K, L>
+where
+    L: Lock,
+{
+    /// Create a new map with this configuration, which uses the default hasher.
+    ///
+    /// # Panics
+    /// Panics if the number of shards is zero.
+    pub(crate) fn new<F, R>(config: Config, factory: F, is_removable: R) -> Self
+    where
+        F: Fn(&K) -> L::Value + Send + Sync + 'static,
+        R: Fn(&L::Value) -> bool + Send + Sync + 'static,
+    {
+        Self::with_hasher(config, RandomState::default(), factory, is_removable)
+    }
+}
+
+impl<K, L, S> LockMap<K, L, S>
+where
+    L: Lock,
+{
+    /// Create a new map with this configuration, which hashes keys using
+    /// `hasher`.
+    ///
+    /// # Panics
+    /// Panics if the number of shards is zero.
+    pub(crate) fn with_hasher<F, R>(config: Config, hasher: S, factory: F, is_removable: R) -> Self
+    where
+        S: Clone,
+        F: Fn(&K) -> L::Value + Send + Sync + 'static,
+        R: Fn(&L::Value) -> bool + Send + Sync + 'static,
+    {
+        assert!(config.shards > 0, "number of shards must not be zero");
+        let capacity = (config.capacity + config.shards - 1) / config.shards;
+        Self {
+            shards: (0..config.shards)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        locks: HashMap::with_capacity_and_hasher(capacity, hasher.clone()),
+                        released: VecDeque::new(),
+                    })
+                })
+                .collect(),
+            hasher,
+            factory: Box::new(factory),
+            is_removable: Box::new(is_removable),
+            config,
+            len: AtomicUsize::new(0),
+            accesses: AtomicUsize::new(0),
+            metrics: Metrics::default(),
+            #[cfg(feature = "deadlock-detection")]
+            detector: None,
+            #[cfg(feature = "tracing")]
+            key_formatter: None,
+        }
+    }
+
+    /// Detect deadlocks between the holders of the keys using this detector.
+    #[cfg(feature = "deadlock-detection")]
+    pub(crate) fn with_detector(mut self, detector: Option<Detector<K>>) -> Self {
+        self.detector = detector.map(Arc::new);
+        self
+    }
+
+    /// Record keys in the spans of acquisitions using this formatter.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn with_key_formatter(mut self, key_formatter: Option<KeyFormatter<K>>) -> Self {
+        self.key_formatter = key_formatter;
+        self
+    }
+
+    /// Return the shards of the map, e.g. for debug output.
+    pub(crate) fn shards(&self) -> &[Mutex<Shard<K, L, S>>] {
+        &self.shards
+    }
+
+    /// Return the number of entries in all shards.
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Return the statistics of the map and its entries.
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Return the configuration of the map.
+    pub(crate) fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Return the deadlock detector of the map, if enabled.
+    #[cfg(feature = "deadlock-detection")]
+    pub(crate) fn detector(&self) -> Option<&Arc<Detector<K>>> {
+        self.detector.as_ref()
+    }
+
+    /// Return the formatter for keys in spans, if keys are recorded.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn key_formatter(&self) -> Option<&KeyFormatter<K>> {
+        self.key_formatter.as_ref()
+    }
+}
+
+impl<K, L, S> LockMap<K, L, S>
+where
+    K: Eq + Hash + Clone,
+    L: Lock,
+    S: BuildHasher,
+{
+    /// Return a reference to the entry for this key, creating it if necessary.
+    /// Afterwards, runs automatic clean up according to the [CleanupPolicy].
+    pub(crate) fn entry(self: &Arc<Self>, key: K) -> EntryRef<K, L, S> {
+        self.entry_cow(Cow::Owned(key))
+    }
+
+    /// Return a reference to the entry for a borrowed form of a key, like
+    /// [`entry`](Self::entry). The key is only converted into an owned key if
+    /// the entry has to be created.
+    pub(crate) fn entry_ref<Q>(self: &Arc<Self>, key: &Q) -> EntryRef<K, L, S>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        self.entry_cow(Cow::Borrowed(key))
+    }
+
+    fn entry_cow<Q>(self: &Arc<Self>, key: Cow<'_, Q>) -> EntryRef<K, L, S>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let shard = self.shard_index(&*key);
+        let mut locks = self.shard(shard);
+        let lookup: &dyn Lookup<Q> = &Borrowed(&*key);
+        let (key, lock) = match locks.locks.get_key_value(lookup) {
+            Some((key, slot)) => (Arc::clone(key), Arc::clone(&slot.lock)),
+            None => {
+                let key = Arc::new(key.into_owned());
+                let lock = Arc::new(L::new((self.factory)(&key)));
+                let slot = Slot {
+                    lock: Arc::clone(&lock),
+                    released: Instant::now(),
+                    queued: false,
+                };
+                locks.locks.insert(Arc::clone(&key), slot);
+                self.len.fetch_add(1, Ordering::Relaxed);
+                (key, lock)
+            }
+        };
+        drop(locks);
+
+        self.clean_up_on_access();
+
+        EntryRef {
+            map: Arc::clone(self),
+            shard,
+            key: Some(key),
+            lock: Some(lock),
+        }
+    }
+}
+
+impl<K, L, S> LockMap<K, L, S>
+where
+    K: Eq + Hash,
+    L: Lock,
+    S: BuildHasher,
+{
+    /// Remove all locks that are not referenced outside of the map, whose value
+    /// is removable and which have been unused for the grace period. Each
+    /// shard is locked only while it is cleaned up.
+    ///
+    /// A lock is only ever cloned out of the map while its shard is locked, so
+    /// a strong count of one guarantees that there are neither guards nor
+    /// pending acquirers for this key, and that none can appear before the
+    /// entry is removed.
+    pub(crate) fn clean_up(&self) {
+        let trace = CleanUp::start();
+        let now = Instant::now();
+        let (mut scanned, mut removed) = (0, 0);
+        for idx in 0..self.shards.len() {
+            let mut shard = self.shard(idx);
+            let Shard { locks, released } = &mut *shard;
+            let len = locks.len();
+            locks.retain(|_, slot| !self.is_unused(slot, now));
+            if locks.len() < len {
+                // a queued key must not outlive its entry, as it would be
+                // mistaken for the entry of the key if it is inserted again
+                released.retain(|key| locks.contains_key(&**key));
+            }
+            scanned += len;
+            removed += len - locks.len();
+            self.record_removed(len - locks.len());
+        }
+        self.metrics.record_clean_up();
+        trace.finish(scanned, removed);
+    }
+
+    /// Call `f` for the key and lock of every entry, locking one shard at a
+    /// time.
+    #[cfg(any(feature = "stats", feature = "tracking"))]
+    pub(crate) fn for_each(&self, mut f: impl FnMut(&K, &L)) {
+        for idx in 0..self.shards.len() {
+            let shard = self.shard(idx);
+            shard
+                .locks
+                .iter()
+                .for_each(|(key, slot)| f(key, &slot.lock));
+        }
+    }
+
+    /// Call `f` for the lock of the entry for this key, if there is one,
+    /// without creating it.
+    #[cfg(feature = "tracking")]
+    pub(crate) fn with_lock<R>(&self, key: &K, f: impl FnOnce(&L) -> R) -> Option<R> {
+        let shard = self.shard(self.shard_index(key));
+        shard.locks.get(key).map(|slot| f(&slot.lock))
+    }
+
+    /// Run automatic clean up after an access, if the [CleanupPolicy] asks for
+    /// it.
+    fn clean_up_on_access(&self) {
+        match self.config.cleanup {
+            CleanupPolicy::Interval(n) => {
+                let accesses = self.accesses.fetch_add(1, Ordering::Relaxed);
+                if accesses % n.max(1) == 0 && self.above_threshold() {
+                    self.clean_up();
+                }
+            }
+            CleanupPolicy::Incremental(n) => {
+                let accesses = self.accesses.fetch_add(1, Ordering::Relaxed);
+                self.clean_up_released(accesses % self.shards.len(), n);
+            }
+            CleanupPolicy::OnRelease | CleanupPolicy::Disabled => {}
+        }
+    }
+
+    /// Check up to `n` released keys of this shard and remove their entries if
+    /// they are unused. Keys whose grace period has not passed yet are queued
+    /// again.
+    fn clean_up_released(&self, idx: usize, n: usize) {
+        let now = Instant::now();
+        let mut shard = self.shard(idx);
+        let Shard { locks, released } = &mut *shard;
+        if released.is_empty() {
+            return;
+        }
+        let trace = CleanUp::start();
+        let (mut scanned, mut removed) = (0, 0);
+        for _ in 0..n {
+            if !self.above_threshold() {
+                break;
+            }
+            let key = match released.pop_front() {
+                Some(key) => key,
+                None => break,
+            };
+            scanned += 1;
+            let slot = match locks.get_mut(&*key) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            if self.in_grace_period(slot, now) {
+                released.push_back(key);
+            } else if self.is_unused(slot, now) {
+                locks.remove(&*key);
+                removed += 1;
+                self.record_removed(1);
+            } else {
+                // the entry is queued again when it is released the next time
+                slot.queued = false;
+            }
+        }
+        trace.finish(scanned, removed);
+    }
+
+    /// Return the index of the shard this key belongs to.
+    fn shard_index<Q>(&self, key: &Q) -> usize
+    where
+        Q: Hash + ?Sized,
+    {
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        // the shards select buckets by the low bits of the same hash, so the
+        // shard is selected by the high bits of a multiplicative hash to keep
+        // keys of the same shard spread over its buckets
+        let hash = hasher.finish().wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+        // truncating the hash is fine, as it is only used to select a shard
+        #[allow(clippy::cast_possible_truncation)]
+        let hash = hash as usize;
+        hash % self.shards.len()
+    }
+
+    /// Lock the shard with this index.
+    fn shard(&self, idx: usize) -> MutexGuard<'_, Shard<K, L, S>> {
+        self.shards[idx].lock()
+    }
+
+    /// Handle the release of a reference to the entry for this key, removing
+    /// or queueing the entry according to the [CleanupPolicy] if it is unused
+    /// now.
+    fn release(&self, shard: &mut Shard<K, L, S>, key: Arc<K>) {
+        let slot = match shard.locks.get_mut(&*key) {
+            Some(slot) if Arc::strong_count(&slot.lock) == 1 => slot,
+            _ => return,
+        };
+        if self.config.grace_period.is_some() {
+            slot.released = Instant::now();
+        }
+
+        match self.config.cleanup {
+            CleanupPolicy::OnRelease => {
+                let released = slot.released;
+                if self.above_threshold() && self.is_unused(slot, released) {
+                    shard.locks.remove(&*key);
+                    self.record_removed(1);
+                }
+            }
+            CleanupPolicy::Incremental(_) if !slot.queued => {
+                slot.queued = true;
+                shard.released.push_back(key);
+            }
+            _ => {}
+        }
+    }
+
+    /// Check whether this lock is referenced only by the map, its value is
+    /// removable and it has been unused for the grace period.
+    fn is_unused(&self, slot: &mut Slot<L>, now: Instant) -> bool {
+        !self.in_grace_period(slot, now)
+            && Arc::get_mut(&mut slot.lock)
+                .map_or(false, |lock| (self.is_removable)(lock.get_mut()))
+    }
+
+    /// Check whether this entry has been released too recently to be removed.
+    fn in_grace_period(&self, slot: &Slot<L>, now: Instant) -> bool {
+        self.config.grace_period.map_or(false, |grace_period| {
+            now.saturating_duration_since(slot.released) < grace_period
+        })
+    }
+
+    /// Check whether the map holds more entries than the clean up threshold.
+    fn above_threshold(&self) -> bool {
+        self.len() > self.config.threshold
+    }
+
+    /// Record the removal of this number of entries.
+    fn record_removed(&self, entries: usize) {
+        self.len.fetch_sub(entries, Ordering::Relaxed);
+        self.metrics.record_removed(entries);
+    }
+}
+
+/// A key or a borrowed form of it, which is used to look up the shared keys of
+/// the map. `Arc<K>` can only borrow as `K`, so lookups by a borrowed form `Q`
+/// go through this trait object instead, which both can borrow as.
+trait Lookup<Q: ?Sized> {
+    /// Return the borrowed form of the key.
+    fn key(&self) -> &Q;
+}
+
+impl<K, Q> Lookup<Q> for Arc<K>
+where
+    K: Borrow<Q>,
+    Q: ?Sized,
+{
+    fn key(&self) -> &Q {
+        (**self).borrow()
+    }
+}
+
+/// A borrowed form of a key, which is used to look up the shared keys of the
+/// map.
+struct Borrowed<'a, Q: ?Sized>(&'a Q);
+
+impl<Q: ?Sized> Lookup<Q> for Borrowed<'_, Q> {
+    fn key(&self) -> &Q {
+        self.0
+    }
+}
+
+impl<'a, K, Q> Borrow<dyn Lookup<Q> + 'a> for Arc<K>
+where
+    K: Borrow<Q> + 'a,
+    Q: ?Sized + 'a,
+{
+    fn borrow(&self) -> &(dyn Lookup<Q> + 'a) {
+        self
+    }
+}
+
+impl<Q: Hash + ?Sized> Hash for dyn Lookup<Q> + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+impl<Q: Eq + ?Sized> PartialEq for dyn Lookup<Q> + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<Q: Eq + ?Sized> Eq for dyn Lookup<Q> + '_ {}
+
+/// Return the default number of shards, which is a few times the available
+/// parallelism to keep the chance of contention low.
+fn default_shards() -> usize {
+    let parallelism = compat::available_parallelism();
+    (parallelism * 4).next_power_of_two()
+}
+
+/// A reference to the entry of a key, which keeps the entry alive. When the
+/// last reference is dropped, the entry is removed from the map or queued for
+/// clean up according to the [CleanupPolicy].
+pub(crate) struct EntryRef<K, L, S = RandomState>
+where
+    K: Eq + Hash,
+    L: Lock,
+    S: BuildHasher,
+{
+    /// The map the entry belongs to.
+    map: Arc<LockMap<K, L, S>>,
+    /// The index of the shard the entry belongs to.
+    shard: usize,
+    /// The key of the entry. Only [None] while the reference is dropped.
+    key: Option<Arc<K>>,
+    /// The lock of the entry. Only [None] while the reference is dropped.
+    lock: Option<Arc<L>>,
+}
+
+impl<K, L, S> EntryRef<K, L, S>
+where
+    K: Eq + Hash,
+    L: Lock,
+    S: BuildHasher,
+{
+    /// Return the key of the entry.
+    pub(crate) fn key(&self) -> &K {
+        self.key.as_deref().expect("key is only taken on drop")
+    }
+
+    /// Return the statistics of the map the entry belongs to.
+    pub(crate) fn metrics(&self) -> &Metrics {
+        self.map.metrics()
+    }
+
+    /// Return the configuration of the map the entry belongs to.
+    pub(crate) fn config(&self) -> &Config {
+        self.map.config()
+    }
+
+    /// Return the deadlock detector of the map the entry belongs to, if
+    /// enabled.
+    #[cfg(feature = "deadlock-detection")]
+    pub(crate) fn detector(&self) -> Option<&Arc<Detector<K>>> {
+        self.map.detector()
+    }
+
+    /// Return the formatter for keys in spans of the map the entry belongs
+    /// to, if keys are recorded.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn key_formatter(&self) -> Option<&KeyFormatter<K>> {
+        self.map.key_formatter()
+    }
+
+    /// Return the lock of the entry.
+    pub(crate) fn lock(&self) -> &Arc<L> {
+        self.lock.as_ref().expect("lock is only taken on drop")
+    }
+}
+
+impl<K, L, S> Drop for EntryRef<K, L, S>
+where
+    K: Eq + Hash,
+    L: Lock,
+    S: BuildHasher,
+{
+    fn drop(&mut self) {
+        let mut shard = self.map.shard(self.shard);
+        // the reference must be dropped while the shard is locked, so no other
+        // reference can be created before the entry is checked
+        drop(self.lock.take());
+        if let Some(key) = self.key.take() {
+            self.map.release(&mut shard, key);
+        }
+    }
+}
@@ -0,0 +1,554 @@
+use alloc::{borrow::ToOwned, sync::Arc};
+#[cfg(feature = "std")]
+use core::time::Duration;
+use core::{
+    borrow::Borrow,
+    fmt,
+    hash::{BuildHasher, Hash},
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+#[cfg(feature = "time")]
+use tokio::time::Instant;
+
+#[cfg(feature = "time")]
+use crate::error::TimeoutError;
+use crate::{
+    backend::{self, Mutex, MutexGuard},
+    builder::map_config,
+    compat::RandomState,
+    error::TryLockError,
+    map::{CleanupPolicy, Config, EntryRef, Lock, LockMap},
+};
+
+/// An async mutual exclusion lock, that locks based on a key, while allowing
+/// other keys to lock independently. Based on a
+/// [HashMap](std::collections::HashMap) of async mutexes.
+///
+/// Offers the exclusive access of a [KeyRwLock](crate::KeyRwLock) without
+/// tracking readers. Each key owns a value of type `V`, which is created lazily
+/// on first access and can be accessed through the returned guards. The entry
+/// for a key is removed as soon as the last guard or pending acquirer for this
+/// key goes away and its value is removable.
+///
+/// Keys are hashed using `S`, which can be set using
+/// [`KeyMutex::with_hasher`] or [`KeyMutexBuilder::hasher`].
+pub struct KeyMutex<K, V = (), S = RandomState> {
+    /// The map of locks, which is shared with all guards.
+    inner: Arc<LockMap<K, Mutex<V>, S>>,
+}
+
+impl<V> Lock for Mutex<V> {
+    type Value = V;
+
+    fn new(value: V) -> Self {
+        Self::new(value)
+    }
+
+    fn get_mut(&mut self) -> &mut V {
+        self.get_mut()
+    }
+}
+
+impl<K, V, S> Default for KeyMutex<K, V, S>
+where
+    V: Default + PartialEq + 'static,
+    S: BuildHasher + Clone + Default,
+{
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K, V, S> fmt::Debug for KeyMutex<K, V, S>
+where
+    K: fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyMutex")
+            .field("shards", &self.inner.shards())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K> KeyMutex<K> {
+    /// Create new instance of a [KeyMutex]
+    ///
+    /// To protect a value for each key, use [`KeyMutex::default`] (values are
+    /// created using [Default] and only removed if they are equal to the
+    /// default value) or [`KeyMutex::with_factory`].
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K, V> KeyMutex<K, V>
+where
+    V: Default + PartialEq + 'static,
+{
+    /// Create new instance of a [KeyMutex] whose map of locks is split into
+    /// this number of shards. Keys are assigned to shards by their hash and
+    /// keys in different shards never contend with each other. By default, a
+    /// few times the available parallelism is used.
+    ///
+    /// # Panics
+    /// Panics if `shards` is zero.
+    #[must_use]
+    pub fn with_shards(shards: usize) -> Self {
+        Self::builder().shards(shards).build()
+    }
+}
+
+impl<K, V, S> KeyMutex<K, V, S>
+where
+    V: Default + PartialEq + 'static,
+    S: BuildHasher + Clone,
+{
+    /// Create new instance of a [KeyMutex] that hashes keys using `hasher`,
+    /// e.g. a faster hasher for keys that are already uniformly distributed
+    /// or a deterministic hasher for reproducible tests.
+    #[must_use]
+    pub fn with_hasher(hasher: S) -> Self {
+        KeyMutexBuilder::new().hasher(hasher).build()
+    }
+
+    /// Create new instance of a [KeyMutex] that hashes keys using `hasher`
+    /// and can hold `capacity` keys without reallocating.
+    #[must_use]
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        KeyMutexBuilder::new()
+            .capacity(capacity)
+            .hasher(hasher)
+            .build()
+    }
+}
+
+impl<K, V> KeyMutex<K, V> {
+    /// Return a builder to create a [KeyMutex] with a custom configuration,
+    /// e.g. to change the [CleanupPolicy].
+    #[must_use]
+    pub fn builder() -> KeyMutexBuilder<K, V> {
+        KeyMutexBuilder::new()
+    }
+
+    /// Create new instance of a [KeyMutex] that creates values using
+    /// `factory`. Unused entries are only removed if `is_removable` returns
+    /// `true` for their value.
+    #[must_use]
+    pub fn with_factory<F, R>(factory: F, is_removable: R) -> Self
+    where
+        F: Fn() -> V + Send + Sync + 'static,
+        R: Fn(&V) -> bool + Send + Sync + 'static,
+    {
+        Self::builder().build_with_factory(factory, is_removable)
+    }
+}
+
+impl<K, V, S> KeyMutex<K, V, S>
+where
+    K: Eq + Hash + Send + Clone,
+    S: BuildHasher,
+{
+    /// Lock this key, returning a guard.
+    pub async fn lock(&self, key: K) -> KeyMutexGuard<K, V, S> {
+        KeyMutexGuard::acquire(self.inner.entry(key)).await
+    }
+
+    /// Lock this key like [`KeyMutex::lock`], taking a borrowed form of the
+    /// key.
+    pub async fn lock_ref<Q>(&self, key: &Q) -> KeyMutexGuard<K, V, S>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        KeyMutexGuard::acquire(self.inner.entry_ref(key)).await
+    }
+
+    /// Try lock this key, returning immediately.
+    pub async fn try_lock(&self, key: K) -> Result<KeyMutexGuard<K, V, S>, TryLockError> {
+        KeyMutexGuard::try_acquire(self.inner.entry(key))
+    }
+
+    /// Try lock this key like [`KeyMutex::try_lock`], taking a borrowed form
+    /// of the key.
+    pub async fn try_lock_ref<Q>(&self, key: &Q) -> Result<KeyMutexGuard<K, V, S>, TryLockError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        KeyMutexGuard::try_acquire(self.inner.entry_ref(key))
+    }
+
+    /// Clean up by removing locks that are neither locked nor waited on.
+    ///
+    /// This is usually not necessary, as entries are removed automatically
+    /// when they are released. It is only needed if values can become
+    /// removable while they are not locked, e.g. through interior mutability.
+    pub async fn clean(&self) {
+        self.inner.clean_up();
+    }
+}
+
+#[cfg(feature = "time")]
+#[cfg_attr(docsrs, doc(cfg(feature = "time")))]
+impl<K, V, S> KeyMutex<K, V, S>
+where
+    K: Eq + Hash + Send + Clone,
+    S: BuildHasher,
+{
+    /// Lock this key, giving up if the lock could not be acquired within
+    /// `timeout`.
+    pub async fn lock_timeout(
+        &self,
+        key: K,
+        timeout: Duration,
+    ) -> Result<KeyMutexGuard<K, V, S>, TimeoutError<K>> {
+        self.lock_timeout_at(key, Instant::now() + timeout).await
+    }
+
+    /// Lock this key, giving up if the lock could not be acquired before
+    /// `deadline`. If it times out, the pending acquisition is dropped, which
+    /// releases its reference to the entry.
+    pub async fn lock_timeout_at(
+        &self,
+        key: K,
+        deadline: Instant,
+    ) -> Result<KeyMutexGuard<K, V, S>, TimeoutError<K>> {
+        let start = Instant::now();
+        tokio::time::timeout_at(deadline, self.lock(key.clone()))
+            .await
+            .map_err(|_| TimeoutError::new(key, start.elapsed()))
+    }
+}
+
+/// Builder for a [KeyMutex] with a custom configuration. Returned by
+/// [`KeyMutex::builder`].
+///
+/// Offers the options of a [KeyRwLockBuilder](crate::KeyRwLockBuilder) that
+/// apply to a [KeyMutex], i.e. all options but the ones for tracking readers
+/// and writers.
+///
+/// # Example
+/// ```
+/// # use std::time::Duration;
+/// # use key_rwlock::{CleanupPolicy, KeyMutex};
+/// # #[cfg(feature = "std")] {
+/// let lock = KeyMutex::<String>::builder()
+///     .shards(16)
+///     .capacity(1024)
+///     .cleanup(CleanupPolicy::Incremental(4))
+///     .cleanup_threshold(128)
+///     .grace_period(Duration::from_secs(1))
+///     .build();
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct KeyMutexBuilder<K, V = (), S = RandomState> {
+    /// The configuration of the map of locks.
+    config: Config,
+    /// Hashes the keys.
+    hasher: S,
+    _phantom: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> KeyMutexBuilder<K, V> {
+    fn new() -> Self {
+        Self {
+            config: Config::default(),
+            hasher: RandomState::default(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<K, V, S> KeyMutexBuilder<K, V, S> {
+    /// Set the hasher used to hash keys, both to assign them to shards and
+    /// within the shards. Defaults to the same hasher as
+    /// [`KeyRwLockBuilder::hasher`](crate::KeyRwLockBuilder::hasher).
+    #[must_use]
+    pub fn hasher<T>(self, hasher: T) -> KeyMutexBuilder<K, V, T> {
+        KeyMutexBuilder {
+            config: self.config,
+            hasher,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Set the number of shards the map of locks is split into, like
+    /// [`KeyRwLockBuilder::shards`](crate::KeyRwLockBuilder::shards).
+    ///
+    /// # Panics
+    /// Panics if `shards` is zero.
+    #[must_use]
+    pub fn shards(mut self, shards: usize) -> Self {
+        assert!(shards > 0, "number of shards must not be zero");
+        self.config.shards = shards;
+        self
+    }
+
+    /// Set the number of keys the map of locks can hold without reallocating.
+    /// The capacity is split evenly between the shards.
+    #[must_use]
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.config.capacity = capacity;
+        self
+    }
+
+    /// Set when unused entries are removed automatically. Defaults to
+    /// [`CleanupPolicy::OnRelease`].
+    #[must_use]
+    pub fn cleanup(mut self, cleanup: CleanupPolicy) -> Self {
+        self.config.cleanup = cleanup;
+        self
+    }
+
+    /// Only remove entries automatically while the map holds more than
+    /// `threshold` entries, so up to `threshold` unused entries are kept for
+    /// reuse. Defaults to zero.
+    #[must_use]
+    pub fn cleanup_threshold(mut self, threshold: usize) -> Self {
+        self.config.threshold = threshold;
+        self
+    }
+
+    /// Only remove entries that have been unused for at least `grace_period`,
+    /// like
+    /// [`KeyRwLockBuilder::grace_period`](crate::KeyRwLockBuilder::grace_period).
+    /// This also applies to [`KeyMutex::clean`].
+    #[cfg(feature = "std")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+    #[must_use]
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.config.grace_period = Some(grace_period);
+        self
+    }
+
+    /// Build a [KeyMutex] that creates values using [Default] and only
+    /// removes entries if their value is equal to the default value.
+    #[must_use]
+    pub fn build(self) -> KeyMutex<K, V, S>
+    where
+        V: Default + PartialEq + 'static,
+        S: BuildHasher + Clone,
+    {
+        self.build_with_factory(V::default, |value| *value == V::default())
+    }
+
+    /// Build a [KeyMutex] that creates values using `factory`. Unused entries
+    /// are only removed if `is_removable` returns `true` for their value.
+    #[must_use]
+    pub fn build_with_factory<F, R>(self, factory: F, is_removable: R) -> KeyMutex<K, V, S>
+    where
+        S: BuildHasher + Clone,
+        F: Fn() -> V + Send + Sync + 'static,
+        R: Fn(&V) -> bool + Send + Sync + 'static,
+    {
+        KeyMutex {
+            inner: Arc::new(LockMap::with_hasher(
+                map_config(self.config),
+                self.hasher,
+                move |_| factory(),
+                is_removable,
+            )),
+        }
+    }
+}
+
+/// RAII structure used to release the exclusive access of a key when dropped.
+/// Returned by [`KeyMutex::lock`] and [`KeyMutex::try_lock`].
+pub struct KeyMutexGuard<K, V = (), S = RandomState>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// The guard of the underlying lock. Declared before `entry`, so the lock
+    /// is released before the entry is checked for removal.
+    guard: MutexGuard<V>,
+    /// The entry this guard belongs to.
+    entry: EntryRef<K, Mutex<V>, S>,
+}
+
+impl<K, V, S> KeyMutexGuard<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// Wait until the lock of this entry is acquired.
+    async fn acquire(entry: EntryRef<K, Mutex<V>, S>) -> Self {
+        let guard = backend::lock(Arc::clone(entry.lock())).await;
+        Self { guard, entry }
+    }
+
+    /// Acquire the lock of this entry if it is free.
+    fn try_acquire(entry: EntryRef<K, Mutex<V>, S>) -> Result<Self, TryLockError> {
+        let guard = backend::try_lock(entry.lock()).ok_or(TryLockError(()))?;
+        Ok(Self { guard, entry })
+    }
+
+    /// Return the key this guard has locked.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        self.entry.key()
+    }
+}
+
+impl<K, V, S> Deref for KeyMutexGuard<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.guard
+    }
+}
+
+impl<K, V, S> DerefMut for KeyMutexGuard<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    fn deref_mut(&mut self) -> &mut V {
+        &mut self.guard
+    }
+}
+
+impl<K, V, S> fmt::Debug for KeyMutexGuard<K, V, S>
+where
+    K: Eq + Hash + fmt::Debug,
+    V: fmt::Debug,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyMutexGuard")
+            .field("key", self.key())
+            .field("value", &**self)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_basic_funcionality() {
+        let lock = KeyMutex::new();
+
+        let foo = lock.lock("foo").await;
+        assert_eq!(foo.key(), &"foo");
+        let _bar = lock.lock("bar").await;
+
+        assert!(lock.try_lock("foo").await.is_err());
+        assert!(lock.try_lock("bar").await.is_err());
+        assert!(lock.try_lock("baz").await.is_ok());
+
+        drop(foo);
+        assert!(lock.try_lock("foo").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_values() {
+        let lock = KeyMutex::<_, i32>::default();
+
+        *lock.lock("foo").await += 42;
+        assert_eq!(*lock.lock("foo").await, 42);
+        assert_eq!(lock.inner.len(), 1);
+
+        *lock.lock("foo").await = 0;
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_clean_up() {
+        let lock = KeyMutex::new();
+
+        let foo = lock.lock("foo").await;
+        let waiting = lock.lock("foo");
+        assert!(tokio::time::timeout(Duration::from_millis(10), waiting)
+            .await
+            .is_err());
+        assert_eq!(lock.inner.len(), 1);
+
+        drop(foo);
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_lock_ref() {
+        let lock = KeyMutex::<String>::new();
+
+        let foo = lock.lock_ref("foo").await;
+        assert_eq!(foo.key(), "foo");
+        assert!(lock.try_lock("foo".to_owned()).await.is_err());
+        assert!(lock.try_lock_ref("bar").await.is_ok());
+
+        drop(foo);
+        assert!(lock.try_lock_ref("foo").await.is_ok());
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let lock = KeyMutex::<_>::builder()
+            .shards(1)
+            .capacity(16)
+            .cleanup(CleanupPolicy::Disabled)
+            .hasher(RandomState::default())
+            .build();
+
+        drop(lock.lock("foo").await);
+        assert_eq!(lock.inner.len(), 1);
+        lock.clean().await;
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn test_grace_period() {
+        let lock = KeyMutex::<_>::builder()
+            .shards(1)
+            .grace_period(Duration::from_millis(50))
+            .build();
+
+        drop(lock.lock("foo").await);
+        assert_eq!(lock.inner.len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        drop(lock.lock("bar").await);
+        assert_eq!(lock.inner.len(), 1);
+    }
+
+    #[cfg(feature = "time")]
+    #[tokio::test]
+    async fn test_timeout() {
+        let lock = KeyMutex::<_, i32>::default();
+
+        let mut foo = lock
+            .lock_timeout("foo", Duration::from_millis(10))
+            .await
+            .unwrap();
+        *foo = 42;
+        let err = lock
+            .lock_timeout("foo", Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert_eq!(err.key(), &"foo");
+        assert_eq!(lock.inner.len(), 1);
+
+        drop(foo);
+        assert_eq!(
+            *lock
+                .lock_timeout("foo", Duration::from_millis(10))
+                .await
+                .unwrap(),
+            42
+        );
+    }
+}
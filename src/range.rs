@@ -0,0 +1,487 @@
+//! Reader-writer lock for ranges of ordered keys, e.g. byte ranges of a file
+//! or offsets of a log.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{fmt, mem, ops::Range};
+
+pub use crate::error::TryLockError;
+use crate::{
+    backend::{self, Event},
+    compat::{Mutex, MutexGuard},
+    LockMode,
+};
+
+/// A reader-writer lock, that locks half-open ranges `start..end` of keys.
+/// Overlapping ranges conflict like a single key of a
+/// [KeyRwLock](crate::KeyRwLock), while disjoint ranges can be locked
+/// independently. Based on a [BTreeMap] of the disjoint segments of keys
+/// covered by held ranges, so the lock only grows with the number of held
+/// ranges instead of the number of keys they cover, and locking or releasing a
+/// range only visits the segments it overlaps.
+///
+/// Empty ranges never conflict with any other range. Fairness is not
+/// guaranteed: a constant stream of readers can starve a writer.
+///
+/// # Example
+/// ```
+/// use key_rwlock::range::RangeRwLock;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let lock = RangeRwLock::new();
+///
+/// let _header = lock.write_range(0..512).await;
+/// assert!(lock.try_write_range(512..1024).await.is_ok());
+/// assert!(lock.try_read_range(256..768).await.is_err());
+/// # }
+/// ```
+pub struct RangeRwLock<K> {
+    /// The held ranges, which are shared with all guards.
+    inner: Arc<Ranges<K>>,
+}
+
+/// The state of a [RangeRwLock].
+struct Ranges<K> {
+    /// The held ranges.
+    held: Mutex<Held<K>>,
+    /// Notified whenever a range is released.
+    released: Event,
+}
+
+/// The held ranges of a [RangeRwLock], stored as the disjoint segments of keys
+/// they cover. Keys covered by the same holders form a single segment, so
+/// adjacent segments always differ in their holders.
+struct Held<K> {
+    /// The segments of keys that are held, keyed by their start.
+    segments: BTreeMap<K, Segment<K>>,
+}
+
+/// A segment of keys that are held by the same holders.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment<K> {
+    /// The exclusive end of the segment.
+    end: K,
+    /// The mode in which the keys are held.
+    mode: LockMode,
+    /// Number of ranges that hold the keys, which is always one for writers.
+    holders: usize,
+}
+
+impl<K> Ranges<K> {
+    /// Lock the held ranges.
+    fn held(&self) -> MutexGuard<'_, Held<K>> {
+        self.held.lock()
+    }
+}
+
+impl<K> Held<K>
+where
+    K: Ord + Clone,
+{
+    /// Return whether this range conflicts with a held range. Only the
+    /// segments overlapping this range are visited, and a writer stops at the
+    /// first one.
+    fn conflicts(&self, range: &Range<K>, mode: LockMode) -> bool {
+        if range.start >= range.end {
+            return false;
+        }
+        let before = self
+            .segments
+            .range(..&range.start)
+            .next_back()
+            .filter(|(_, segment)| segment.end > range.start);
+        before
+            .into_iter()
+            .chain(self.segments.range(range.clone()))
+            .any(|(_, segment)| mode == LockMode::Write || segment.mode == LockMode::Write)
+    }
+
+    /// Lock this range, if it does not conflict with a held range.
+    fn try_insert(&mut self, range: &Range<K>, mode: LockMode) -> bool {
+        if self.conflicts(range, mode) {
+            return false;
+        }
+        if range.start >= range.end {
+            return true;
+        }
+        self.split_at(&range.start);
+        self.split_at(&range.end);
+
+        // count this range as a holder of the covered segments and fill the
+        // gaps between them with new segments
+        let mut gaps = Vec::new();
+        let mut next = range.start.clone();
+        for (start, segment) in self.segments.range_mut(range.clone()) {
+            if next < *start {
+                gaps.push(next..start.clone());
+            }
+            segment.holders += 1;
+            next = segment.end.clone();
+        }
+        if next < range.end {
+            gaps.push(next..range.end.clone());
+        }
+        for gap in gaps {
+            let segment = Segment {
+                end: gap.end,
+                mode,
+                holders: 1,
+            };
+            self.segments.insert(gap.start, segment);
+        }
+
+        self.merge_at(&range.start);
+        self.merge_at(&range.end);
+        true
+    }
+
+    /// Release this range, which must be held.
+    fn remove(&mut self, range: &Range<K>) {
+        if range.start >= range.end {
+            return;
+        }
+        self.split_at(&range.start);
+        self.split_at(&range.end);
+
+        let mut unused = Vec::new();
+        for (start, segment) in self.segments.range_mut(range.clone()) {
+            segment.holders -= 1;
+            if segment.holders == 0 {
+                unused.push(start.clone());
+            }
+        }
+        for start in unused {
+            self.segments.remove(&start);
+        }
+
+        // the holders of the segments within the range changed uniformly, so
+        // only the segments at its bounds can have become equal to their
+        // neighbours
+        self.merge_at(&range.start);
+        self.merge_at(&range.end);
+    }
+
+    /// Split the segment containing this key, so a segment starts at it.
+    fn split_at(&mut self, key: &K) {
+        let segment = match self.segments.range_mut(..key).next_back() {
+            Some((_, segment)) if segment.end > *key => segment,
+            _ => return,
+        };
+        let tail = Segment {
+            end: mem::replace(&mut segment.end, key.clone()),
+            ..segment.clone()
+        };
+        self.segments.insert(key.clone(), tail);
+    }
+
+    /// Merge the segment starting at this key into the segment ending at it,
+    /// if both are held by the same holders.
+    fn merge_at(&mut self, key: &K) {
+        let next = match self.segments.get(key) {
+            Some(next) => next.clone(),
+            None => return,
+        };
+        match self.segments.range_mut(..key).next_back() {
+            Some((_, prev))
+                if prev.end == *key && prev.mode == next.mode && prev.holders == next.holders =>
+            {
+                prev.end = next.end;
+            }
+            _ => return,
+        }
+        self.segments.remove(key);
+    }
+}
+
+impl<K> Default for RangeRwLock<K> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Ranges {
+                held: Mutex::new(Held {
+                    segments: BTreeMap::new(),
+                }),
+                released: Event::new(),
+            }),
+        }
+    }
+}
+
+impl<K> fmt::Debug for RangeRwLock<K>
+where
+    K: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let held = self.inner.held();
+        f.debug_struct("RangeRwLock")
+            .field(
+                "held",
+                &held
+                    .segments
+                    .iter()
+                    .map(|(start, segment)| (start..&segment.end, segment.mode, segment.holders))
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl<K> RangeRwLock<K> {
+    /// Create new instance of a [RangeRwLock]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K> RangeRwLock<K>
+where
+    K: Ord + Clone,
+{
+    /// Lock this range with shared read access, returning a guard. Waits until
+    /// no writer holds an overlapping range.
+    pub async fn read_range(&self, range: Range<K>) -> RangeRwLockGuard<K> {
+        self.acquire(range, LockMode::Read).await
+    }
+
+    /// Lock this range with exclusive write access, returning a guard. Waits
+    /// until no other task holds an overlapping range.
+    pub async fn write_range(&self, range: Range<K>) -> RangeRwLockGuard<K> {
+        self.acquire(range, LockMode::Write).await
+    }
+
+    /// Attempt to lock this range with shared read access, without waiting.
+    pub async fn try_read_range(
+        &self,
+        range: Range<K>,
+    ) -> Result<RangeRwLockGuard<K>, TryLockError> {
+        self.try_acquire(range, LockMode::Read)
+    }
+
+    /// Attempt to lock this range with exclusive write access, without
+    /// waiting.
+    pub async fn try_write_range(
+        &self,
+        range: Range<K>,
+    ) -> Result<RangeRwLockGuard<K>, TryLockError> {
+        self.try_acquire(range, LockMode::Write)
+    }
+
+    /// Lock this range, waiting until it does not conflict with a held range.
+    async fn acquire(&self, range: Range<K>, mode: LockMode) -> RangeRwLockGuard<K> {
+        backend::wait_until(&self.inner.released, || {
+            self.try_acquire(range.clone(), mode).ok()
+        })
+        .await
+    }
+
+    /// Lock this range, if it does not conflict with a held range.
+    fn try_acquire(
+        &self,
+        range: Range<K>,
+        mode: LockMode,
+    ) -> Result<RangeRwLockGuard<K>, TryLockError> {
+        if !self.inner.held().try_insert(&range, mode) {
+            return Err(TryLockError(()));
+        }
+        Ok(RangeRwLockGuard {
+            ranges: Arc::clone(&self.inner),
+            range,
+            mode,
+        })
+    }
+}
+
+/// RAII structure used to release the access to a range when dropped.
+/// Returned by [`RangeRwLock::read_range`] and [`RangeRwLock::write_range`].
+pub struct RangeRwLockGuard<K>
+where
+    K: Ord + Clone,
+{
+    /// The held ranges of the lock.
+    ranges: Arc<Ranges<K>>,
+    /// The range this guard holds.
+    range: Range<K>,
+    /// Whether the range is held with shared read or exclusive write access.
+    mode: LockMode,
+}
+
+impl<K> RangeRwLockGuard<K>
+where
+    K: Ord + Clone,
+{
+    /// Return the range this guard holds.
+    #[must_use]
+    pub fn range(&self) -> &Range<K> {
+        &self.range
+    }
+
+    /// Return whether this guard holds shared read or exclusive write access.
+    #[must_use]
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+}
+
+impl<K> Drop for RangeRwLockGuard<K>
+where
+    K: Ord + Clone,
+{
+    fn drop(&mut self) {
+        self.ranges.held().remove(&self.range);
+        backend::notify_all(&self.ranges.released);
+    }
+}
+
+impl<K> fmt::Debug for RangeRwLockGuard<K>
+where
+    K: Ord + Clone + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RangeRwLockGuard")
+            .field("range", &self.range)
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_basic_funcionality() {
+        let lock = RangeRwLock::new();
+
+        let _write = lock.write_range(10..20).await;
+        let _read = lock.read_range(30..40).await;
+
+        assert!(lock.try_read_range(15..16).await.is_err());
+        assert!(lock.try_read_range(0..11).await.is_err());
+        assert!(lock.try_write_range(19..25).await.is_err());
+        assert!(lock.try_write_range(0..100).await.is_err());
+
+        // adjacent ranges do not overlap
+        assert!(lock.try_write_range(0..10).await.is_ok());
+        assert!(lock.try_write_range(20..30).await.is_ok());
+
+        assert!(lock.try_read_range(35..45).await.is_ok());
+        assert!(lock.try_write_range(35..45).await.is_err());
+        assert!(lock.try_write_range(40..50).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_empty_range() {
+        let lock = RangeRwLock::new();
+
+        let _empty = lock.write_range(5..5).await;
+        assert!(lock.try_write_range(0..10).await.is_ok());
+
+        let _all = lock.write_range(0..10).await;
+        assert!(lock.try_write_range(5..5).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_release() {
+        let lock = RangeRwLock::new();
+
+        let first = lock.read_range(0..10).await;
+        let second = lock.read_range(0..10).await;
+        assert_eq!(first.range(), &(0..10));
+        assert_eq!(first.mode(), LockMode::Read);
+
+        drop(first);
+        assert!(lock.try_write_range(5..6).await.is_err());
+        drop(second);
+        assert!(lock.try_write_range(5..6).await.is_ok());
+        assert!(lock.inner.held().segments.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_nested_readers() {
+        let lock = RangeRwLock::new();
+
+        let outer = lock.read_range(0..100).await;
+        let inner = lock.read_range(10..20).await;
+        assert!(lock.try_write_range(50..60).await.is_err());
+        drop(outer);
+        assert!(lock.try_write_range(50..60).await.is_ok());
+        assert!(lock.try_write_range(15..16).await.is_err());
+        drop(inner);
+        assert!(lock.try_write_range(0..100).await.is_ok());
+        assert!(lock.inner.held().segments.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_many_disjoint_ranges() {
+        let lock = RangeRwLock::new();
+
+        // appending ranges only ever visits the last segment
+        let mut guards = Vec::new();
+        for idx in 0..20_000u64 {
+            guards.push(lock.try_write_range(2 * idx..2 * idx + 1).await.unwrap());
+        }
+        assert_eq!(lock.inner.held().segments.len(), 20_000);
+        assert!(lock.try_write_range(1..2).await.is_ok());
+        assert!(lock.try_read_range(39_998..39_999).await.is_err());
+        assert!(lock.try_read_range(39_999..50_000).await.is_ok());
+
+        let mut odd = Vec::new();
+        for idx in 0..20_000u64 {
+            odd.push(lock.try_read_range(2 * idx + 1..2 * idx + 2).await.unwrap());
+        }
+        guards.retain(|guard| guard.range().start % 4 == 0);
+        assert!(lock.try_read_range(2..3).await.is_ok());
+        assert!(lock.try_write_range(0..1).await.is_err());
+        drop(guards);
+        assert_eq!(lock.inner.held().segments.len(), 20_000);
+
+        // adjacent ranges held by the same holders are merged into one segment
+        let mut even = Vec::new();
+        for idx in 0..20_000u64 {
+            even.push(lock.try_read_range(2 * idx..2 * idx + 1).await.unwrap());
+        }
+        assert_eq!(lock.inner.held().segments.len(), 1);
+        assert!(lock.try_write_range(39_999..40_000).await.is_err());
+
+        drop(odd);
+        assert_eq!(lock.inner.held().segments.len(), 20_000);
+        drop(even);
+        assert!(lock.inner.held().segments.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wait_for_overlap() {
+        let lock = Arc::new(RangeRwLock::new());
+
+        let guard = lock.write_range(100..200).await;
+        let task = tokio::spawn({
+            let lock = Arc::clone(&lock);
+            async move {
+                let _guard = lock.read_range(150..250).await;
+            }
+        });
+        // a disjoint range is not blocked by the waiting task
+        assert!(lock.try_write_range(300..400).await.is_ok());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!task.is_finished());
+
+        drop(guard);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_acquirer() {
+        let lock = RangeRwLock::new();
+
+        let guard = lock.write_range(0..10).await;
+        let acquire = lock.write_range(5..15);
+        assert!(tokio::time::timeout(Duration::from_millis(10), acquire)
+            .await
+            .is_err());
+
+        drop(guard);
+        assert!(lock.try_write_range(0..15).await.is_ok());
+    }
+}
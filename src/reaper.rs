@@ -0,0 +1,81 @@
+use std::{
+    hash::{BuildHasher, Hash},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use tokio::{
+    task::JoinHandle,
+    time::{self, Instant, MissedTickBehavior},
+};
+
+use crate::KeyRwLock;
+
+/// Handle of a background task that periodically removes unused entries from a
+/// [KeyRwLock]. Returned by [`KeyRwLock::spawn_reaper`].
+///
+/// Dropping the handle detaches the task, which keeps running until the lock
+/// is dropped.
+#[derive(Debug)]
+pub struct ReaperHandle {
+    /// The spawned task.
+    task: JoinHandle<()>,
+}
+
+impl ReaperHandle {
+    /// Stop the task. A clean up pass that is currently running is completed
+    /// first.
+    pub fn stop(self) {
+        self.task.abort();
+    }
+
+    /// Check whether the task has stopped, e.g. because the lock has been
+    /// dropped.
+    #[must_use]
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl<K, V, S> KeyRwLock<K, V, S>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+    S: BuildHasher + Send + Sync + 'static,
+{
+    /// Spawn a task on the current tokio runtime that removes unused entries
+    /// every `interval`, so clean up never has to run when a key is locked.
+    /// This is most useful with [`CleanupPolicy::Disabled`] or a grace
+    /// period.
+    ///
+    /// The task only holds a weak reference to the lock and stops once the
+    /// lock has been dropped.
+    ///
+    /// # Panics
+    /// Panics if called outside of a tokio runtime or if `interval` is zero.
+    ///
+    /// [`CleanupPolicy::Disabled`]: crate::CleanupPolicy::Disabled
+    pub fn spawn_reaper(self: &Arc<Self>, interval: Duration) -> ReaperHandle {
+        let lock = Arc::downgrade(self);
+        let mut interval = time::interval_at(Instant::now() + interval, interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ReaperHandle {
+            task: tokio::spawn(reap(lock, interval)),
+        }
+    }
+}
+
+/// Remove unused entries from the lock on every tick until it is dropped.
+async fn reap<K, V, S>(lock: Weak<KeyRwLock<K, V, S>>, mut interval: time::Interval)
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    loop {
+        interval.tick().await;
+        match lock.upgrade() {
+            Some(lock) => lock.inner.clean_up(),
+            None => break,
+        }
+    }
+}
@@ -0,0 +1,338 @@
+//! Semaphore that limits the number of concurrent holders per key.
+
+use alloc::sync::Arc;
+use core::{fmt, hash::Hash};
+
+pub use crate::error::{TooManyPermitsError, TryLockError};
+use crate::{
+    backend::{self, Semaphore, SemaphorePermit},
+    compat::{HashMap, RandomState},
+    map::{Config, EntryRef, Lock, LockMap},
+};
+
+/// A semaphore, that limits the number of concurrent permits per key, while
+/// allowing other keys to be acquired independently. Based on a
+/// hash map of async semaphores.
+///
+/// Each key allows up to a default number of permits, which can be overridden
+/// for specific keys. Like for a [KeyRwLock](crate::KeyRwLock), the entry for
+/// a key is removed as soon as its last permit is dropped.
+///
+/// # Example
+/// ```
+/// use key_rwlock::semaphore::KeySemaphore;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let semaphore = KeySemaphore::with_limits(2, [("slow.example.com", 1)]);
+///
+/// let _first = semaphore.acquire("example.com").await;
+/// let _second = semaphore.acquire("example.com").await;
+/// assert!(semaphore.try_acquire("example.com").await.is_err());
+///
+/// let _slow = semaphore.acquire("slow.example.com").await;
+/// assert!(semaphore.try_acquire("slow.example.com").await.is_err());
+/// # }
+/// ```
+pub struct KeySemaphore<K> {
+    /// The map of semaphores, which is shared with all permits.
+    inner: Arc<LockMap<K, KeyPermits>>,
+}
+
+/// The semaphore of a single key.
+struct KeyPermits {
+    /// The semaphore limiting the number of permits of the key.
+    semaphore: Arc<Semaphore>,
+    /// The number of permits the semaphore was created with.
+    limit: u32,
+}
+
+impl Lock for KeyPermits {
+    type Value = u32;
+
+    fn new(limit: u32) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit as usize)),
+            limit,
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut u32 {
+        &mut self.limit
+    }
+}
+
+impl fmt::Debug for KeyPermits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPermits")
+            .field("available", &backend::available_permits(&self.semaphore))
+            .field("limit", &self.limit)
+            .finish()
+    }
+}
+
+impl<K> fmt::Debug for KeySemaphore<K>
+where
+    K: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeySemaphore")
+            .field("shards", &self.inner.shards())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K> KeySemaphore<K> {
+    /// Create new instance of a [KeySemaphore] that allows up to `limit`
+    /// concurrent permits for each key.
+    ///
+    /// # Panics
+    /// Panics if `limit` is zero.
+    #[must_use]
+    pub fn new(limit: u32) -> Self {
+        assert_limit(limit);
+        Self {
+            inner: Arc::new(LockMap::new(Config::default(), move |_| limit, |_| true)),
+        }
+    }
+}
+
+impl<K> KeySemaphore<K>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+{
+    /// Create new instance of a [KeySemaphore] that allows up to `limit`
+    /// concurrent permits for each key, except for the keys in `overrides`,
+    /// which allow the given number of permits instead.
+    ///
+    /// # Panics
+    /// Panics if `limit` or one of the overridden limits is zero.
+    #[must_use]
+    pub fn with_limits(limit: u32, overrides: impl IntoIterator<Item = (K, u32)>) -> Self {
+        assert_limit(limit);
+        let mut overrides_map = HashMap::with_hasher(RandomState::default());
+        for (key, limit) in overrides {
+            assert_limit(limit);
+            overrides_map.insert(key, limit);
+        }
+        let overrides = overrides_map;
+        Self {
+            inner: Arc::new(LockMap::new(
+                Config::default(),
+                move |key| overrides.get(key).copied().unwrap_or(limit),
+                |_| true,
+            )),
+        }
+    }
+}
+
+impl<K> KeySemaphore<K>
+where
+    K: Eq + Hash + Send + Clone,
+{
+    /// Acquire a single permit for this key, waiting until one is available.
+    pub async fn acquire(&self, key: K) -> KeySemaphorePermit<K> {
+        let entry = self.inner.entry(key);
+        let semaphore = Arc::clone(&entry.lock().semaphore);
+        KeySemaphorePermit {
+            _permit: backend::acquire_many(semaphore, 1).await,
+            n: 1,
+            entry,
+        }
+    }
+
+    /// Acquire `n` permits for this key at once, waiting until enough are
+    /// available.
+    ///
+    /// Returns an error without waiting if `n` exceeds the limit of this key,
+    /// as the permits could never be acquired.
+    pub async fn acquire_many(
+        &self,
+        key: K,
+        n: u32,
+    ) -> Result<KeySemaphorePermit<K>, TooManyPermitsError> {
+        let entry = self.inner.entry(key);
+        let lock = entry.lock();
+        if n > lock.limit {
+            return Err(TooManyPermitsError::new(n, lock.limit));
+        }
+        let permit = backend::acquire_many(Arc::clone(&lock.semaphore), n).await;
+        Ok(KeySemaphorePermit {
+            _permit: permit,
+            n,
+            entry,
+        })
+    }
+
+    /// Attempt to acquire a single permit for this key, without waiting.
+    pub async fn try_acquire(&self, key: K) -> Result<KeySemaphorePermit<K>, TryLockError> {
+        self.try_acquire_many(key, 1).await
+    }
+
+    /// Attempt to acquire `n` permits for this key at once, without waiting.
+    /// Also fails if `n` exceeds the limit of this key.
+    pub async fn try_acquire_many(
+        &self,
+        key: K,
+        n: u32,
+    ) -> Result<KeySemaphorePermit<K>, TryLockError> {
+        let entry = self.inner.entry(key);
+        let permit =
+            backend::try_acquire_many(&entry.lock().semaphore, n).ok_or(TryLockError(()))?;
+        Ok(KeySemaphorePermit {
+            _permit: permit,
+            n,
+            entry,
+        })
+    }
+
+    /// Remove all entries that are not in use.
+    ///
+    /// This is usually not necessary, as entries are removed automatically
+    /// when their last permit is dropped.
+    pub async fn clean(&self) {
+        self.inner.clean_up();
+    }
+}
+
+/// Assert that a limit allows at least one permit, as no permit could ever be
+/// acquired otherwise.
+fn assert_limit(limit: u32) {
+    assert!(limit > 0, "limit of permits must not be zero");
+}
+
+/// RAII structure used to release permits of a key when dropped. Returned by
+/// [`KeySemaphore::acquire`] and [`KeySemaphore::acquire_many`].
+pub struct KeySemaphorePermit<K>
+where
+    K: Eq + Hash,
+{
+    /// The permits of the underlying semaphore. Declared before `entry`, so
+    /// they are released before the entry is checked for removal.
+    _permit: SemaphorePermit,
+    /// The number of permits held.
+    n: u32,
+    /// The entry these permits belong to.
+    entry: EntryRef<K, KeyPermits>,
+}
+
+impl<K> KeySemaphorePermit<K>
+where
+    K: Eq + Hash,
+{
+    /// Return the key these permits belong to.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        self.entry.key()
+    }
+
+    /// Return the number of permits held.
+    #[must_use]
+    pub fn num_permits(&self) -> u32 {
+        self.n
+    }
+}
+
+impl<K> fmt::Debug for KeySemaphorePermit<K>
+where
+    K: Eq + Hash + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeySemaphorePermit")
+            .field("key", self.key())
+            .field("permits", &self.n)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_basic_funcionality() {
+        let semaphore = KeySemaphore::new(2);
+
+        let foo = semaphore.acquire("foo").await;
+        assert_eq!(foo.key(), &"foo");
+        assert_eq!(foo.num_permits(), 1);
+        let _bar = semaphore.acquire_many("bar", 2).await.unwrap();
+
+        assert!(semaphore.try_acquire("bar").await.is_err());
+        assert!(semaphore.try_acquire_many("foo", 2).await.is_err());
+        let _foo2 = semaphore.try_acquire("foo").await.unwrap();
+        assert!(semaphore.try_acquire("foo").await.is_err());
+
+        drop(foo);
+        assert!(semaphore.try_acquire("foo").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let semaphore = KeySemaphore::with_limits(1, [("foo", 3)]);
+
+        let _foo = semaphore.acquire_many("foo", 3).await.unwrap();
+        let _bar = semaphore.acquire("bar").await;
+        assert!(semaphore.try_acquire("foo").await.is_err());
+        assert!(semaphore.try_acquire("bar").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exceed_limit() {
+        let semaphore = KeySemaphore::new(2);
+        let err = semaphore.acquire_many("foo", 3).await.unwrap_err();
+        assert_eq!((err.requested(), err.limit()), (3, 2));
+        assert!(semaphore.try_acquire_many("foo", 3).await.is_err());
+        assert_eq!(semaphore.inner.len(), 0);
+    }
+
+    #[test]
+    #[should_panic = "limit of permits must not be zero"]
+    fn test_zero_limit() {
+        let _ = KeySemaphore::with_limits(1, [("foo", 0)]);
+    }
+
+    #[tokio::test]
+    async fn test_clean_up() {
+        let semaphore = KeySemaphore::new(1);
+
+        let foo = semaphore.acquire("foo").await;
+        let waiting = semaphore.acquire("foo");
+        assert!(tokio::time::timeout(Duration::from_millis(10), waiting)
+            .await
+            .is_err());
+        assert_eq!(semaphore.inner.len(), 1);
+
+        drop(foo);
+        assert_eq!(semaphore.inner.len(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrency() {
+        let semaphore = Arc::new(KeySemaphore::new(3));
+        let holders = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..16)
+            .map(|_| {
+                let semaphore = Arc::clone(&semaphore);
+                let holders = Arc::clone(&holders);
+                tokio::spawn(async move {
+                    let _permit = semaphore.acquire("foo").await;
+                    let current = holders.fetch_add(1, Ordering::SeqCst);
+                    assert!(current < 3);
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    holders.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(semaphore.inner.len(), 0);
+    }
+}
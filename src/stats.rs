@@ -0,0 +1,275 @@
+//! Collection of lock statistics. Without the `stats` feature, all recorders
+//! are zero-sized and do nothing.
+
+#[cfg(feature = "stats")]
+pub use imp::{Stats, WaitHistogram};
+#[cfg(feature = "stats")]
+pub(crate) use imp::{Hold, Holders, Metrics, Stopwatch};
+#[cfg(not(feature = "stats"))]
+pub(crate) use noop::{Hold, Holders, Metrics, Stopwatch};
+
+/// The kind of access a guard holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HoldMode {
+    /// Shared or upgradable read access.
+    Read,
+    /// Exclusive write access.
+    Write,
+}
+
+#[cfg(feature = "stats")]
+mod imp {
+    use std::{
+        sync::{
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use super::HoldMode;
+
+    /// Number of buckets of a [WaitHistogram].
+    pub(super) const BUCKETS: usize = 24;
+
+    /// A snapshot of the statistics of a [KeyRwLock](crate::KeyRwLock).
+    /// Returned by [`KeyRwLock::stats`](crate::KeyRwLock::stats).
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    #[non_exhaustive]
+    pub struct Stats {
+        /// Number of keys currently in the map.
+        pub keys: usize,
+        /// Number of keys currently locked with shared or upgradable read
+        /// access.
+        pub read_locked_keys: usize,
+        /// Number of keys currently locked with exclusive write access.
+        pub write_locked_keys: usize,
+        /// Number of acquisitions that succeeded without waiting.
+        pub fast_acquisitions: u64,
+        /// Number of acquisitions that had to wait for the key to be released.
+        pub contended_acquisitions: u64,
+        /// Number of clean up passes over the whole map.
+        pub clean_ups: u64,
+        /// Number of entries removed from the map.
+        pub removed_entries: u64,
+        /// Distribution of the wait times of contended acquisitions.
+        pub wait_times: WaitHistogram,
+    }
+
+    /// A histogram of wait times with exponentially growing buckets. The first
+    /// bucket counts waits below one microsecond, every following bucket
+    /// doubles the upper bound and the last bucket counts all remaining waits.
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct WaitHistogram {
+        /// The number of waits in each bucket.
+        counts: [u64; BUCKETS],
+    }
+
+    impl WaitHistogram {
+        /// Return an iterator over the exclusive upper bound and the number of
+        /// waits of each bucket. The upper bound of the last bucket is
+        /// [`Duration::MAX`].
+        pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+            self.counts.iter().enumerate().map(|(idx, &count)| {
+                let bound = if idx == BUCKETS - 1 {
+                    Duration::MAX
+                } else {
+                    Duration::from_micros(1 << idx)
+                };
+                (bound, count)
+            })
+        }
+
+        /// Return the total number of recorded waits.
+        #[must_use]
+        pub fn count(&self) -> u64 {
+            self.counts.iter().sum()
+        }
+    }
+
+    /// Counters shared by all keys of a map.
+    #[derive(Debug, Default)]
+    pub(crate) struct Metrics {
+        /// Number of acquisitions that succeeded without waiting.
+        fast_acquisitions: AtomicU64,
+        /// Number of acquisitions that had to wait for the key to be released.
+        contended_acquisitions: AtomicU64,
+        /// Number of clean up passes over the whole map.
+        clean_ups: AtomicU64,
+        /// Number of entries removed from the map.
+        removed_entries: AtomicU64,
+        /// The number of waits in each bucket of the wait time histogram.
+        wait_times: [AtomicU64; BUCKETS],
+    }
+
+    impl Metrics {
+        /// Record an acquisition that did not have to wait.
+        pub(crate) fn record_fast(&self) {
+            self.fast_acquisitions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        /// Record an acquisition that had to wait since the stopwatch was
+        /// started.
+        pub(crate) fn record_contended(&self, stopwatch: Stopwatch) {
+            self.contended_acquisitions.fetch_add(1, Ordering::Relaxed);
+            self.record_wait(stopwatch.0.elapsed());
+        }
+
+        /// Record a wait time in the histogram.
+        pub(super) fn record_wait(&self, wait: Duration) {
+            let micros = u64::try_from(wait.as_micros()).unwrap_or(u64::MAX);
+            let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+            self.wait_times[bucket.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        }
+
+        /// Record a clean up pass over the whole map.
+        pub(crate) fn record_clean_up(&self) {
+            self.clean_ups.fetch_add(1, Ordering::Relaxed);
+        }
+
+        /// Record the removal of this number of entries.
+        pub(crate) fn record_removed(&self, entries: usize) {
+            self.removed_entries
+                .fetch_add(entries as u64, Ordering::Relaxed);
+        }
+
+        /// Return a snapshot of the counters. The key counts are left empty.
+        pub(crate) fn snapshot(&self) -> Stats {
+            Stats {
+                fast_acquisitions: self.fast_acquisitions.load(Ordering::Relaxed),
+                contended_acquisitions: self.contended_acquisitions.load(Ordering::Relaxed),
+                clean_ups: self.clean_ups.load(Ordering::Relaxed),
+                removed_entries: self.removed_entries.load(Ordering::Relaxed),
+                wait_times: self.wait_times(),
+                ..Stats::default()
+            }
+        }
+
+        /// Return a snapshot of the wait time histogram.
+        fn wait_times(&self) -> WaitHistogram {
+            let mut histogram = WaitHistogram::default();
+            for (count, recorded) in histogram.counts.iter_mut().zip(&self.wait_times) {
+                *count = recorded.load(Ordering::Relaxed);
+            }
+            histogram
+        }
+    }
+
+    /// Measures the wait time of a contended acquisition.
+    #[derive(Debug)]
+    pub(crate) struct Stopwatch(Instant);
+
+    impl Stopwatch {
+        pub(crate) fn start() -> Self {
+            Self(Instant::now())
+        }
+    }
+
+    /// Number of guards currently holding a single key.
+    #[derive(Debug, Default)]
+    pub(crate) struct Holders(Arc<[AtomicUsize; 2]>);
+
+    impl Holders {
+        /// Return the number of guards holding the key in this mode.
+        pub(crate) fn count(&self, mode: HoldMode) -> usize {
+            self.0[mode as usize].load(Ordering::Relaxed)
+        }
+    }
+
+    /// Registers a guard in the [Holders] of its key while it is alive.
+    #[derive(Debug)]
+    pub(crate) struct Hold {
+        /// The holder counts of the key, indexed by mode.
+        holders: Arc<[AtomicUsize; 2]>,
+        /// The mode the guard is currently counted in.
+        mode: HoldMode,
+    }
+
+    impl Hold {
+        pub(crate) fn new(holders: &Holders, mode: HoldMode) -> Self {
+            holders.0[mode as usize].fetch_add(1, Ordering::Relaxed);
+            Self {
+                holders: Arc::clone(&holders.0),
+                mode,
+            }
+        }
+
+        /// Change the mode of the guard, e.g. when it is upgraded.
+        pub(crate) fn set_mode(&mut self, mode: HoldMode) {
+            self.holders[mode as usize].fetch_add(1, Ordering::Relaxed);
+            self.holders[self.mode as usize].fetch_sub(1, Ordering::Relaxed);
+            self.mode = mode;
+        }
+    }
+
+    impl Drop for Hold {
+        fn drop(&mut self) {
+            self.holders[self.mode as usize].fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+mod noop {
+    use super::HoldMode;
+
+    #[derive(Debug, Default)]
+    pub(crate) struct Metrics(());
+
+    impl Metrics {
+        pub(crate) fn record_fast(&self) {}
+
+        pub(crate) fn record_contended(&self, _stopwatch: Stopwatch) {}
+
+        pub(crate) fn record_clean_up(&self) {}
+
+        pub(crate) fn record_removed(&self, _entries: usize) {}
+    }
+
+    #[derive(Debug)]
+    pub(crate) struct Stopwatch;
+
+    impl Stopwatch {
+        pub(crate) fn start() -> Self {
+            Self
+        }
+    }
+
+    #[derive(Debug, Default)]
+    pub(crate) struct Holders(());
+
+    #[derive(Debug)]
+    pub(crate) struct Hold;
+
+    impl Hold {
+        pub(crate) fn new(_holders: &Holders, _mode: HoldMode) -> Self {
+            Self
+        }
+
+        pub(crate) fn set_mode(&mut self, _mode: HoldMode) {}
+    }
+}
+
+#[cfg(all(test, feature = "stats"))]
+mod tests {
+    use std::time::Duration;
+
+    use super::imp::{Metrics, BUCKETS};
+
+    #[test]
+    fn test_wait_histogram() {
+        let metrics = Metrics::default();
+        for micros in [0, 1, 3, 1_000_000, u64::MAX] {
+            metrics.record_wait(Duration::from_micros(micros));
+        }
+
+        let buckets = metrics.snapshot().wait_times.buckets().collect::<Vec<_>>();
+        assert_eq!(buckets.len(), BUCKETS);
+        assert_eq!(buckets[0], (Duration::from_micros(1), 1));
+        assert_eq!(buckets[1], (Duration::from_micros(2), 1));
+        assert_eq!(buckets[2], (Duration::from_micros(4), 1));
+        assert_eq!(buckets[20], (Duration::from_micros(1 << 20), 1));
+        assert_eq!(buckets[BUCKETS - 1], (Duration::MAX, 1));
+        assert_eq!(metrics.snapshot().wait_times.count(), 5);
+    }
+}
@@ -0,0 +1,480 @@
+//! Blocking variant of [KeyRwLock](crate::KeyRwLock) for synchronous code,
+//! which does not require an async runtime.
+
+use std::{
+    fmt,
+    hash::Hash,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use parking_lot::{
+    ArcRwLockReadGuard, ArcRwLockUpgradableReadGuard, ArcRwLockWriteGuard, RawRwLock, RwLock,
+};
+
+pub use crate::error::TryLockError;
+use crate::map::{Config, EntryRef, Lock, LockMap};
+
+/// A blocking reader-writer lock, that locks based on a key, while allowing
+/// other keys to lock independently. Based on a
+/// [HashMap](std::collections::HashMap) of [RwLock]s.
+///
+/// Each key owns a value of type `V`, which is created lazily on first access
+/// and can be accessed through the returned guards. The entry for a key is
+/// removed as soon as the last guard for this key is dropped and its value is
+/// removable.
+pub struct KeyRwLock<K, V = ()> {
+    /// The map of locks, which is shared with all guards.
+    inner: Arc<LockMap<K, RwLock<V>>>,
+}
+
+impl<V> Lock for RwLock<V> {
+    type Value = V;
+
+    fn new(value: V) -> Self {
+        Self::new(value)
+    }
+
+    fn get_mut(&mut self) -> &mut V {
+        self.get_mut()
+    }
+}
+
+impl<K, V> Default for KeyRwLock<K, V>
+where
+    V: Default + PartialEq + 'static,
+{
+    fn default() -> Self {
+        Self::with_factory(V::default, |value| *value == V::default())
+    }
+}
+
+impl<K, V> fmt::Debug for KeyRwLock<K, V>
+where
+    K: fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyRwLock")
+            .field("shards", &self.inner.shards())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K> KeyRwLock<K> {
+    /// Create new instance of a [KeyRwLock]
+    ///
+    /// To protect a value for each key, use [`KeyRwLock::default`] or
+    /// [`KeyRwLock::with_factory`].
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K, V> KeyRwLock<K, V>
+where
+    V: Default + PartialEq + 'static,
+{
+    /// Create new instance of a [KeyRwLock] whose map of locks is split into
+    /// this number of shards. Keys are assigned to shards by their hash and
+    /// keys in different shards never contend with each other. By default, a
+    /// few times the available parallelism is used.
+    ///
+    /// # Panics
+    /// Panics if `shards` is zero.
+    #[must_use]
+    pub fn with_shards(shards: usize) -> Self {
+        Self {
+            inner: Arc::new(LockMap::new(
+                Config {
+                    shards,
+                    ..Config::default()
+                },
+                |_| V::default(),
+                |value| *value == V::default(),
+            )),
+        }
+    }
+}
+
+impl<K, V> KeyRwLock<K, V> {
+    /// Create new instance of a [KeyRwLock] that creates values using
+    /// `factory`. Unused entries are only removed if `is_removable` returns
+    /// `true` for their value.
+    #[must_use]
+    pub fn with_factory<F, R>(factory: F, is_removable: R) -> Self
+    where
+        F: Fn() -> V + Send + Sync + 'static,
+        R: Fn(&V) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(LockMap::new(
+                Config::default(),
+                move |_| factory(),
+                is_removable,
+            )),
+        }
+    }
+}
+
+impl<K, V> KeyRwLock<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Lock this key with shared read access, blocking the current thread until
+    /// it can be acquired.
+    pub fn read(&self, key: K) -> KeyRwLockReadGuard<K, V> {
+        let entry = self.inner.entry(key);
+        let guard = entry.lock().read_arc();
+        KeyRwLockReadGuard { guard, entry }
+    }
+
+    /// Lock this key with exclusive write access, blocking the current thread
+    /// until it can be acquired.
+    pub fn write(&self, key: K) -> KeyRwLockWriteGuard<K, V> {
+        let entry = self.inner.entry(key);
+        let guard = entry.lock().write_arc();
+        KeyRwLockWriteGuard { guard, entry }
+    }
+
+    /// Lock this key with upgradable read access, blocking the current thread
+    /// until it can be acquired. Upgradable read access coexists with shared
+    /// read access, but at most one thread can hold upgradable read or
+    /// exclusive write access to a key at a time, so the guard can be upgraded
+    /// to exclusive write access atomically.
+    pub fn upgradable_read(&self, key: K) -> KeyRwLockUpgradableReadGuard<K, V> {
+        let entry = self.inner.entry(key);
+        let guard = entry.lock().upgradable_read_arc();
+        KeyRwLockUpgradableReadGuard { guard, entry }
+    }
+
+    /// Try lock this key with shared read access, returning immediately.
+    pub fn try_read(&self, key: K) -> Result<KeyRwLockReadGuard<K, V>, TryLockError> {
+        let entry = self.inner.entry(key);
+        let guard = entry.lock().try_read_arc().ok_or(TryLockError(()))?;
+        Ok(KeyRwLockReadGuard { guard, entry })
+    }
+
+    /// Try lock this key with exclusive write access, returning immediately.
+    pub fn try_write(&self, key: K) -> Result<KeyRwLockWriteGuard<K, V>, TryLockError> {
+        let entry = self.inner.entry(key);
+        let guard = entry.lock().try_write_arc().ok_or(TryLockError(()))?;
+        Ok(KeyRwLockWriteGuard { guard, entry })
+    }
+
+    /// Try lock this key with upgradable read access, returning immediately.
+    pub fn try_upgradable_read(
+        &self,
+        key: K,
+    ) -> Result<KeyRwLockUpgradableReadGuard<K, V>, TryLockError> {
+        let entry = self.inner.entry(key);
+        let guard = entry
+            .lock()
+            .try_upgradable_read_arc()
+            .ok_or(TryLockError(()))?;
+        Ok(KeyRwLockUpgradableReadGuard { guard, entry })
+    }
+
+    /// Clean up by removing locks that are neither locked nor waited on.
+    ///
+    /// This is usually not necessary, as entries are removed automatically
+    /// when they are released. It is only needed if values can become
+    /// removable while they are not locked, e.g. through interior mutability.
+    pub fn clean(&self) {
+        self.inner.clean_up();
+    }
+}
+
+/// RAII structure used to release the shared read access of a key when
+/// dropped. Returned by [`KeyRwLock::read`] and [`KeyRwLock::try_read`].
+pub struct KeyRwLockReadGuard<K, V = ()>
+where
+    K: Eq + Hash,
+{
+    /// The guard of the underlying lock. Declared before `entry`, so the lock
+    /// is released before the entry is checked for removal.
+    guard: ArcRwLockReadGuard<RawRwLock, V>,
+    /// The entry this guard belongs to.
+    entry: EntryRef<K, RwLock<V>>,
+}
+
+impl<K, V> KeyRwLockReadGuard<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Return the key this guard has locked.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        self.entry.key()
+    }
+}
+
+impl<K, V> Deref for KeyRwLockReadGuard<K, V>
+where
+    K: Eq + Hash,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.guard
+    }
+}
+
+impl<K, V> fmt::Debug for KeyRwLockReadGuard<K, V>
+where
+    K: Eq + Hash + fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyRwLockReadGuard")
+            .field("key", self.key())
+            .field("value", &**self)
+            .finish()
+    }
+}
+
+/// RAII structure used to release the upgradable read access of a key when
+/// dropped. Returned by [`KeyRwLock::upgradable_read`] and
+/// [`KeyRwLock::try_upgradable_read`].
+///
+/// Upgradable read access coexists with shared read access, but excludes
+/// writers and other upgradable readers of the same key.
+pub struct KeyRwLockUpgradableReadGuard<K, V = ()>
+where
+    K: Eq + Hash,
+{
+    /// The guard of the underlying lock. Declared before `entry`, so the lock
+    /// is released before the entry is checked for removal.
+    guard: ArcRwLockUpgradableReadGuard<RawRwLock, V>,
+    /// The entry this guard belongs to.
+    entry: EntryRef<K, RwLock<V>>,
+}
+
+impl<K, V> KeyRwLockUpgradableReadGuard<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Return the key this guard has locked.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        self.entry.key()
+    }
+
+    /// Upgrade to exclusive write access, blocking the current thread until
+    /// all other readers of this key have released their access. No writer
+    /// can lock the key in the meantime, so the value is unchanged when this
+    /// function returns.
+    #[must_use]
+    pub fn upgrade(self) -> KeyRwLockWriteGuard<K, V> {
+        KeyRwLockWriteGuard {
+            guard: ArcRwLockUpgradableReadGuard::upgrade(self.guard),
+            entry: self.entry,
+        }
+    }
+
+    /// Downgrade to shared read access, allowing writers and upgradable
+    /// readers to lock this key again.
+    #[must_use]
+    pub fn downgrade(self) -> KeyRwLockReadGuard<K, V> {
+        KeyRwLockReadGuard {
+            guard: ArcRwLockUpgradableReadGuard::downgrade(self.guard),
+            entry: self.entry,
+        }
+    }
+}
+
+impl<K, V> Deref for KeyRwLockUpgradableReadGuard<K, V>
+where
+    K: Eq + Hash,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.guard
+    }
+}
+
+impl<K, V> fmt::Debug for KeyRwLockUpgradableReadGuard<K, V>
+where
+    K: Eq + Hash + fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyRwLockUpgradableReadGuard")
+            .field("key", self.key())
+            .field("value", &**self)
+            .finish()
+    }
+}
+
+/// RAII structure used to release the exclusive write access of a key when
+/// dropped. Returned by [`KeyRwLock::write`] and [`KeyRwLock::try_write`].
+pub struct KeyRwLockWriteGuard<K, V = ()>
+where
+    K: Eq + Hash,
+{
+    /// The guard of the underlying lock. Declared before `entry`, so the lock
+    /// is released before the entry is checked for removal.
+    guard: ArcRwLockWriteGuard<RawRwLock, V>,
+    /// The entry this guard belongs to.
+    entry: EntryRef<K, RwLock<V>>,
+}
+
+impl<K, V> KeyRwLockWriteGuard<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Return the key this guard has locked.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        self.entry.key()
+    }
+
+    /// Atomically downgrade to shared read access, so no writer can lock the
+    /// key in between.
+    #[must_use]
+    pub fn downgrade(self) -> KeyRwLockReadGuard<K, V> {
+        KeyRwLockReadGuard {
+            guard: ArcRwLockWriteGuard::downgrade(self.guard),
+            entry: self.entry,
+        }
+    }
+
+    /// Atomically downgrade to upgradable read access, so no writer can lock
+    /// the key in between.
+    #[must_use]
+    pub fn downgrade_to_upgradable(self) -> KeyRwLockUpgradableReadGuard<K, V> {
+        KeyRwLockUpgradableReadGuard {
+            guard: ArcRwLockWriteGuard::downgrade_to_upgradable(self.guard),
+            entry: self.entry,
+        }
+    }
+}
+
+impl<K, V> Deref for KeyRwLockWriteGuard<K, V>
+where
+    K: Eq + Hash,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.guard
+    }
+}
+
+impl<K, V> DerefMut for KeyRwLockWriteGuard<K, V>
+where
+    K: Eq + Hash,
+{
+    fn deref_mut(&mut self) -> &mut V {
+        &mut self.guard
+    }
+}
+
+impl<K, V> fmt::Debug for KeyRwLockWriteGuard<K, V>
+where
+    K: Eq + Hash + fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyRwLockWriteGuard")
+            .field("key", self.key())
+            .field("value", &**self)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_basic_funcionality() {
+        let lock = KeyRwLock::new();
+
+        let _foo = lock.write("foo");
+        let _bar = lock.read("bar");
+
+        assert!(lock.try_read("foo").is_err());
+        assert!(lock.try_write("foo").is_err());
+
+        assert!(lock.try_read("bar").is_ok());
+        assert!(lock.try_write("bar").is_err());
+    }
+
+    #[test]
+    fn test_clean_up() {
+        let lock = KeyRwLock::<_, i32>::default();
+
+        *lock.write("foo") = 42;
+        let bar = lock.read("bar");
+        assert_eq!(bar.key(), &"bar");
+        assert_eq!(lock.inner.len(), 2);
+        drop(bar);
+        assert_eq!(lock.inner.len(), 1);
+        assert_eq!(*lock.read("foo"), 42);
+
+        *lock.write("foo") = 0;
+        lock.clean();
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[test]
+    fn test_threads() {
+        let lock = KeyRwLock::new();
+        let holders = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let _guard = lock.write("foo");
+                        assert_eq!(holders.fetch_add(1, Ordering::SeqCst), 0);
+                        thread::yield_now();
+                        holders.fetch_sub(1, Ordering::SeqCst);
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    lock.clean();
+                    thread::yield_now();
+                }
+            });
+        });
+
+        assert_eq!(lock.inner.len(), 0);
+    }
+
+    #[test]
+    fn test_upgrade_downgrade() {
+        let lock = KeyRwLock::<_, i32>::default();
+
+        let foo = lock.upgradable_read("foo");
+        assert!(lock.try_read("foo").is_ok());
+        assert!(lock.try_upgradable_read("foo").is_err());
+        assert!(lock.try_write("foo").is_err());
+
+        let mut foo = foo.upgrade();
+        *foo = 42;
+        assert!(lock.try_read("foo").is_err());
+
+        let foo = foo.downgrade_to_upgradable();
+        assert_eq!(*lock.try_read("foo").unwrap(), 42);
+        assert!(lock.try_write("foo").is_err());
+
+        let foo = foo.downgrade();
+        assert!(lock.try_upgradable_read("foo").is_ok());
+        drop(foo);
+
+        let foo = lock.write("foo").downgrade();
+        assert_eq!(*foo, 42);
+        assert!(lock.try_write("foo").is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! Identification of the tasks that hold and wait for keys, which is used by
+//! deadlock detection and lock tracking. Tasks are identified independently of
+//! the async runtime, by running them in a [TaskScope].
+
+use std::{
+    cell::Cell,
+    fmt,
+    future::Future,
+    num::NonZeroU64,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+/// The id of the next task.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The task that is currently polled on this thread, if any.
+    static CURRENT: Cell<Option<TaskId>> = const { Cell::new(None) };
+}
+
+/// The id of a task, to which guards and waiting acquisitions are attributed
+/// by deadlock detection and lock tracking.
+///
+/// There is no way to identify the current task that works with every async
+/// runtime, so tasks have to be marked explicitly by running their future in
+/// the [scope](TaskId::scope) of an id. Acquisitions outside of any scope are
+/// not attributed to a task.
+///
+/// # Example
+/// ```
+/// use key_rwlock::TaskId;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let id = TaskId::new();
+/// let task = tokio::spawn(id.scope(async { TaskId::current() }));
+/// assert_eq!(task.await.unwrap(), Some(id));
+/// assert_eq!(TaskId::current(), None);
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(NonZeroU64);
+
+impl TaskId {
+    /// Create a new id, which differs from all other ids of this process.
+    ///
+    /// # Panics
+    /// Panics if all ids have been used up, which takes centuries.
+    #[must_use]
+    pub fn new() -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        Self(NonZeroU64::new(id).expect("task ids exhausted"))
+    }
+
+    /// Return the id of the task that is currently polled on this thread, if
+    /// it runs in the [scope](TaskId::scope) of an id.
+    #[must_use]
+    pub fn current() -> Option<Self> {
+        CURRENT.with(Cell::get)
+    }
+
+    /// Run `future` as the task with this id, so all guards it acquires are
+    /// attributed to this task. Scopes can be nested, in which case the
+    /// innermost scope applies.
+    pub fn scope<F>(self, future: F) -> TaskScope<F>
+    where
+        F: Future,
+    {
+        TaskScope {
+            id: self,
+            future: Box::pin(future),
+        }
+    }
+
+    /// Return the id as a number.
+    #[must_use]
+    pub fn as_u64(self) -> u64 {
+        self.0.get()
+    }
+}
+
+impl Default for TaskId {
+    /// Create a new id, like [`TaskId::new`].
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A future that runs as the task with a specific [TaskId]. Returned by
+/// [`TaskId::scope`].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct TaskScope<F> {
+    /// The id of the task.
+    id: TaskId,
+    /// The future of the task.
+    future: Pin<Box<F>>,
+}
+
+impl<F> TaskScope<F> {
+    /// Return the id of the task.
+    #[must_use]
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+}
+
+impl<F> Future for TaskScope<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let _enter = Enter::new(self.id);
+        self.future.as_mut().poll(cx)
+    }
+}
+
+impl<F> fmt::Debug for TaskScope<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskScope")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Marks a task as the current task of this thread while it is alive, and
+/// restores the previous one afterwards, even if polling the task panics.
+struct Enter {
+    /// The task that was current before.
+    previous: Option<TaskId>,
+}
+
+impl Enter {
+    fn new(id: TaskId) -> Self {
+        Self {
+            previous: CURRENT.with(|current| current.replace(Some(id))),
+        }
+    }
+}
+
+impl Drop for Enter {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.previous));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_nested_scopes() {
+        let (outer, inner) = (TaskId::new(), TaskId::new());
+        assert_ne!(outer, inner);
+
+        let ids = outer
+            .scope(async move {
+                let before = TaskId::current();
+                let nested = inner.scope(async { TaskId::current() }).await;
+                (before, nested, TaskId::current())
+            })
+            .await;
+        assert_eq!(ids, (Some(outer), Some(inner), Some(outer)));
+        assert_eq!(TaskId::current(), None);
+    }
+}
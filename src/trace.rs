@@ -0,0 +1,380 @@
+//! Emission of `tracing` spans and events for acquisitions, releases and clean
+//! up passes. Without the `tracing` feature, all recorders are zero-sized and
+//! do nothing.
+
+#[cfg(feature = "tracing")]
+pub(crate) use imp::{Acquiring, CleanUp, KeyFormatter, Traced};
+#[cfg(not(feature = "tracing"))]
+pub(crate) use noop::{Acquiring, CleanUp, Traced};
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use std::{
+        fmt,
+        hash::{BuildHasher, Hash},
+        time::Instant,
+    };
+
+    use tracing::{field, span::EnteredSpan, Span};
+
+    use crate::{
+        map::{EntryRef, Lock},
+        stats::HoldMode,
+    };
+
+    /// Formats a key like [fmt::Display::fmt].
+    type Format<K> = Box<dyn Fn(&K, &mut fmt::Formatter<'_>) -> fmt::Result + Send + Sync>;
+
+    /// Formats keys for the `key` field of the spans.
+    pub(crate) struct KeyFormatter<K>(Format<K>);
+
+    impl<K> KeyFormatter<K> {
+        pub(crate) fn new<F>(formatter: F) -> Self
+        where
+            F: Fn(&K, &mut fmt::Formatter<'_>) -> fmt::Result + Send + Sync + 'static,
+        {
+            Self(Box::new(formatter))
+        }
+    }
+
+    impl<K> fmt::Debug for KeyFormatter<K> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("KeyFormatter").finish_non_exhaustive()
+        }
+    }
+
+    /// A key that is displayed using a [KeyFormatter].
+    struct FormattedKey<'a, K> {
+        /// The key to display.
+        key: &'a K,
+        /// Formats the key.
+        formatter: &'a KeyFormatter<K>,
+    }
+
+    impl<K> fmt::Display for FormattedKey<'_, K> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            (self.formatter.0)(self.key, f)
+        }
+    }
+
+    /// An acquisition that has not completed yet. Its span lives until the
+    /// acquired guard is released.
+    pub(crate) struct Acquiring {
+        /// The span of the acquisition.
+        span: Span,
+        /// When the acquisition started.
+        since: Instant,
+        /// The access that is acquired.
+        mode: HoldMode,
+    }
+
+    impl Acquiring {
+        /// Open the span of an acquisition of this entry in this mode.
+        pub(crate) fn start<K, L, S>(entry: &EntryRef<K, L, S>, mode: HoldMode) -> Self
+        where
+            K: Eq + Hash,
+            L: Lock,
+            S: BuildHasher,
+        {
+            let span = tracing::trace_span!(
+                target: "key_rwlock",
+                "lock",
+                key = field::Empty,
+                mode = mode_name(mode),
+            );
+            if let Some(formatter) = entry.key_formatter().filter(|_| !span.is_disabled()) {
+                let key = FormattedKey {
+                    key: entry.key(),
+                    formatter,
+                };
+                span.record("key", field::display(key));
+            }
+            tracing::trace!(target: "key_rwlock", parent: &span, "acquiring key");
+            Self {
+                span,
+                since: Instant::now(),
+                mode,
+            }
+        }
+
+        /// Record that the key is held by someone else, so the acquisition
+        /// has to wait.
+        pub(crate) fn contended(&self) {
+            tracing::debug!(target: "key_rwlock", parent: &self.span, "key is contended");
+        }
+
+        /// Record the completion of the acquisition and start measuring how
+        /// long the key is held.
+        pub(crate) fn acquired(self) -> Traced {
+            tracing::trace!(
+                target: "key_rwlock",
+                parent: &self.span,
+                wait = ?self.since.elapsed(),
+                mode = mode_name(self.mode),
+                "acquired key",
+            );
+            Traced {
+                span: self.span,
+                since: Instant::now(),
+                mode: self.mode,
+            }
+        }
+    }
+
+    /// Keeps the span of a guard open and records its release when dropped.
+    pub(crate) struct Traced {
+        /// The span of the acquisition of the guard.
+        span: Span,
+        /// When the key was acquired.
+        since: Instant,
+        /// The access the guard currently holds.
+        mode: HoldMode,
+    }
+
+    impl Traced {
+        /// Change the mode of the guard, e.g. when it is upgraded.
+        pub(crate) fn set_mode(&mut self, mode: HoldMode) {
+            self.mode = mode;
+            self.span.record("mode", mode_name(mode));
+            tracing::trace!(
+                target: "key_rwlock",
+                parent: &self.span,
+                mode = mode_name(mode),
+                "changed mode",
+            );
+        }
+    }
+
+    impl Drop for Traced {
+        fn drop(&mut self) {
+            tracing::trace!(
+                target: "key_rwlock",
+                parent: &self.span,
+                held = ?self.since.elapsed(),
+                mode = mode_name(self.mode),
+                "released key",
+            );
+        }
+    }
+
+    /// The span of a clean up pass, which is entered while it is alive.
+    pub(crate) struct CleanUp {
+        /// Keeps the span entered.
+        _entered: EnteredSpan,
+    }
+
+    impl CleanUp {
+        /// Open and enter the span of a clean up pass.
+        pub(crate) fn start() -> Self {
+            Self {
+                _entered: tracing::debug_span!(target: "key_rwlock", "clean_up").entered(),
+            }
+        }
+
+        /// Record how many entries have been checked and removed.
+        pub(crate) fn finish(self, scanned: usize, removed: usize) {
+            tracing::debug!(target: "key_rwlock", scanned, removed, "cleaned up entries");
+        }
+    }
+
+    /// Return the name of this mode for the `mode` fields.
+    fn mode_name(mode: HoldMode) -> &'static str {
+        match mode {
+            HoldMode::Read => "read",
+            HoldMode::Write => "write",
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod noop {
+    use core::hash::{BuildHasher, Hash};
+
+    use crate::{
+        map::{EntryRef, Lock},
+        stats::HoldMode,
+    };
+
+    pub(crate) struct Acquiring;
+
+    impl Acquiring {
+        pub(crate) fn start<K, L, S>(_entry: &EntryRef<K, L, S>, _mode: HoldMode) -> Self
+        where
+            K: Eq + Hash,
+            L: Lock,
+            S: BuildHasher,
+        {
+            Self
+        }
+
+        pub(crate) fn contended(&self) {}
+
+        pub(crate) fn acquired(self) -> Traced {
+            Traced
+        }
+    }
+
+    pub(crate) struct Traced;
+
+    impl Traced {
+        pub(crate) fn set_mode(&mut self, _mode: HoldMode) {}
+    }
+
+    pub(crate) struct CleanUp;
+
+    impl CleanUp {
+        pub(crate) fn start() -> Self {
+            Self
+        }
+
+        pub(crate) fn finish(self, _scanned: usize, _removed: usize) {}
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::{
+        fmt::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Event, Metadata, Subscriber,
+    };
+
+    use crate::{CleanupPolicy, KeyRwLock};
+
+    /// Records the fields of all spans and events as strings.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Records>);
+
+    /// The recorded spans and events.
+    #[derive(Default)]
+    struct Records {
+        /// The name and fields of every span, indexed by its id minus one.
+        spans: Mutex<Vec<String>>,
+        /// The fields of every event.
+        events: Mutex<Vec<String>>,
+    }
+
+    /// Appends the recorded fields to a string.
+    struct Fields<'a>(&'a mut String);
+
+    impl Visit for Fields<'_> {
+        #[allow(clippy::use_debug)]
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            write!(self.0, " {}={value:?}", field.name()).unwrap();
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut spans = self.0.spans.lock().unwrap();
+            let mut fields = span.metadata().name().to_owned();
+            span.record(&mut Fields(&mut fields));
+            spans.push(fields);
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let idx = usize::try_from(span.into_u64()).unwrap() - 1;
+            values.record(&mut Fields(&mut self.0.spans.lock().unwrap()[idx]));
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = String::new();
+            event.record(&mut Fields(&mut fields));
+            self.0.events.lock().unwrap().push(fields);
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    /// Return the first word of the message of every recorded event.
+    fn messages(recorder: &Recorder) -> Vec<String> {
+        let events = recorder.0.events.lock().unwrap();
+        events
+            .iter()
+            .map(|event| event.split(' ').nth(1).unwrap().to_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_acquire_and_release() {
+        let recorder = Recorder::default();
+        let _default = tracing::subscriber::set_default(recorder.clone());
+        let lock = KeyRwLock::<_>::builder().trace_keys_debug().build();
+
+        let guard = lock.upgradable_read("foo").await;
+        let _guard = guard.upgrade().await;
+        assert!(lock.try_read("foo").await.is_err());
+
+        let spans = recorder.0.spans.lock().unwrap().clone();
+        assert_eq!(
+            spans,
+            [
+                "lock mode=\"read\" key=\"foo\" mode=\"write\"",
+                "lock mode=\"read\" key=\"foo\"",
+            ]
+        );
+        let events = recorder.0.events.lock().unwrap().clone();
+        assert!(events[1].starts_with(" message=acquired key wait="));
+        assert!(events[1].ends_with(" mode=\"read\""));
+        assert_eq!(events[2], " message=changed mode mode=\"write\"");
+        assert_eq!(events.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_contention_and_clean_up() {
+        let recorder = Recorder::default();
+        let _default = tracing::subscriber::set_default(recorder.clone());
+        let lock = Arc::new(
+            KeyRwLock::<&str>::builder()
+                .cleanup(CleanupPolicy::Disabled)
+                .trace_keys_with(|key, f| f.write_str(&key.to_uppercase()))
+                .build(),
+        );
+
+        let guard = lock.write("foo").await;
+        let waiter = tokio::spawn({
+            let lock = Arc::clone(&lock);
+            async move {
+                let _guard = lock.read("foo").await;
+            }
+        });
+        tokio::task::yield_now().await;
+        drop(guard);
+        waiter.await.unwrap();
+        lock.clean().await;
+
+        assert_eq!(
+            messages(&recorder),
+            [
+                "message=acquiring",
+                "message=acquired",
+                "message=acquiring",
+                "message=key",
+                "message=released",
+                "message=acquired",
+                "message=released",
+                "message=cleaned",
+            ]
+        );
+        let events = recorder.0.events.lock().unwrap();
+        assert!(events[4].contains(" held="));
+        assert_eq!(events[7], " message=cleaned up entries scanned=1 removed=1");
+        let spans = recorder.0.spans.lock().unwrap();
+        assert_eq!(spans[0], "lock mode=\"write\" key=FOO");
+        assert_eq!(spans[2], "clean_up");
+    }
+}
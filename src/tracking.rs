@@ -0,0 +1,296 @@
+//! Tracking of the guards holding and the tasks waiting for each key, so they
+//! can be inspected. Without the `tracking` feature, all recorders are
+//! zero-sized and do nothing.
+
+#[cfg(feature = "tracking")]
+pub use imp::{Acquisition, LockInfo};
+#[cfg(feature = "tracking")]
+pub(crate) use imp::{Tracked, Tracker};
+#[cfg(not(feature = "tracking"))]
+pub(crate) use noop::{Tracked, Tracker};
+
+#[cfg(feature = "tracking")]
+mod imp {
+    #[cfg(feature = "backtrace")]
+    use std::backtrace::Backtrace;
+    use std::{sync::Arc, time::Instant};
+
+    use crate::{compat::Mutex, map::Config, stats::HoldMode, task::TaskId, LockMode};
+
+    /// A guard holding a key or a task waiting for it. Part of a [LockInfo].
+    #[derive(Debug, Clone)]
+    #[non_exhaustive]
+    pub struct Acquisition {
+        /// The access the guard holds or waits for. Upgradable read access
+        /// counts as read access.
+        pub mode: LockMode,
+        /// The metadata passed to the `_with` method that acquired the guard,
+        /// e.g. [`KeyRwLock::write_with`](crate::KeyRwLock::write_with).
+        pub owner: Option<String>,
+        /// The id of the task that acquired the guard, if it was acquired
+        /// inside of a [`TaskId::scope`].
+        pub task: Option<TaskId>,
+        /// When the key was acquired or, for waiters, when the task started
+        /// waiting for it.
+        pub since: Instant,
+        /// The backtrace of the acquisition, if enabled using
+        /// [`KeyRwLockBuilder::capture_backtraces`](crate::KeyRwLockBuilder::capture_backtraces).
+        #[cfg(feature = "backtrace")]
+        #[cfg_attr(docsrs, doc(cfg(feature = "backtrace")))]
+        #[allow(clippy::incompatible_msrv)] // the `backtrace` feature requires rust 1.65
+        pub backtrace: Option<Arc<Backtrace>>,
+    }
+
+    /// The guards holding a key and the tasks waiting for it. Returned by
+    /// [`KeyRwLock::inspect`](crate::KeyRwLock::inspect).
+    #[derive(Debug, Clone, Default)]
+    #[non_exhaustive]
+    pub struct LockInfo {
+        /// The guards holding the key, in the order they acquired it.
+        pub holders: Vec<Acquisition>,
+        /// The tasks waiting for the key, in the order they started waiting.
+        pub waiters: Vec<Acquisition>,
+    }
+
+    impl LockInfo {
+        /// Return whether the key is neither held nor waited for.
+        #[must_use]
+        pub fn is_empty(&self) -> bool {
+            self.holders.is_empty() && self.waiters.is_empty()
+        }
+    }
+
+    /// The acquisitions of a single key.
+    #[derive(Debug, Default)]
+    pub(crate) struct Tracker(Arc<Mutex<Records>>);
+
+    impl Tracker {
+        /// Return a snapshot of the holders and waiters of the key.
+        pub(crate) fn info(&self) -> LockInfo {
+            let mut info = LockInfo::default();
+            for record in &self.0.lock().records {
+                let acquisitions = if record.waiting {
+                    &mut info.waiters
+                } else {
+                    &mut info.holders
+                };
+                acquisitions.push(record.acquisition.clone());
+            }
+            info
+        }
+    }
+
+    /// The acquisitions of a key and the id of the next one.
+    #[derive(Debug, Default)]
+    struct Records {
+        /// The holders and waiters of the key, in the order they started
+        /// waiting.
+        records: Vec<Record>,
+        /// The id of the next acquisition.
+        next_id: u64,
+    }
+
+    /// A single acquisition of a key.
+    #[derive(Debug)]
+    struct Record {
+        /// Identifies the acquisition among the records of the key.
+        id: u64,
+        /// Whether the task is still waiting for the key.
+        waiting: bool,
+        /// The acquisition as returned by [`Tracker::info`].
+        acquisition: Acquisition,
+    }
+
+    /// Registers an acquisition in the [Tracker] of its key while it is alive,
+    /// first as a waiter and then as a holder of the key.
+    #[derive(Debug)]
+    pub(crate) struct Tracked {
+        /// The records of the key.
+        records: Arc<Mutex<Records>>,
+        /// The id of the record of this acquisition.
+        id: u64,
+    }
+
+    impl Tracked {
+        /// Register the current task as waiting for the key in this mode.
+        #[allow(clippy::incompatible_msrv)] // the `backtrace` feature requires rust 1.65
+        pub(crate) fn wait(
+            tracker: &Tracker,
+            config: &Config,
+            mode: HoldMode,
+            owner: Option<String>,
+        ) -> Self {
+            #[cfg(not(feature = "backtrace"))]
+            let _ = config;
+            let acquisition = Acquisition {
+                mode: lock_mode(mode),
+                owner,
+                task: TaskId::current(),
+                since: Instant::now(),
+                #[cfg(feature = "backtrace")]
+                backtrace: config
+                    .capture_backtraces
+                    .then(|| Arc::new(Backtrace::force_capture())),
+            };
+            let mut records = tracker.0.lock();
+            let id = records.next_id;
+            records.next_id += 1;
+            records.records.push(Record {
+                id,
+                waiting: true,
+                acquisition,
+            });
+            drop(records);
+            Self {
+                records: Arc::clone(&tracker.0),
+                id,
+            }
+        }
+
+        /// Register the acquisition as a holder of the key.
+        pub(crate) fn acquired(&mut self) {
+            self.update(|record| {
+                record.waiting = false;
+                record.acquisition.since = Instant::now();
+            });
+        }
+
+        /// Change the mode of the guard, e.g. when it is upgraded.
+        pub(crate) fn set_mode(&mut self, mode: HoldMode) {
+            self.update(|record| record.acquisition.mode = lock_mode(mode));
+        }
+
+        /// Change the record of this acquisition.
+        fn update(&mut self, f: impl FnOnce(&mut Record)) {
+            let mut records = self.records.lock();
+            if let Some(record) = records.records.iter_mut().find(|r| r.id == self.id) {
+                f(record);
+            }
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.records
+                .lock()
+                .records
+                .retain(|record| record.id != self.id);
+        }
+    }
+
+    /// Convert the mode of a guard to the mode reported by [Tracker::info].
+    fn lock_mode(mode: HoldMode) -> LockMode {
+        match mode {
+            HoldMode::Read => LockMode::Read,
+            HoldMode::Write => LockMode::Write,
+        }
+    }
+}
+
+#[cfg(not(feature = "tracking"))]
+mod noop {
+    use alloc::string::String;
+
+    use crate::{map::Config, stats::HoldMode};
+
+    #[derive(Debug, Default)]
+    pub(crate) struct Tracker(());
+
+    #[derive(Debug)]
+    pub(crate) struct Tracked;
+
+    impl Tracked {
+        pub(crate) fn wait(
+            _tracker: &Tracker,
+            _config: &Config,
+            _mode: HoldMode,
+            _owner: Option<String>,
+        ) -> Self {
+            Self
+        }
+
+        pub(crate) fn acquired(&mut self) {}
+
+        pub(crate) fn set_mode(&mut self, _mode: HoldMode) {}
+    }
+}
+
+#[cfg(all(test, feature = "tracking"))]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{KeyRwLock, LockMode, TaskId};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_holders_and_waiters() {
+        let lock = Arc::new(KeyRwLock::new());
+
+        let holder_id = TaskId::new();
+        let holder = tokio::spawn(holder_id.scope({
+            let lock = Arc::clone(&lock);
+            async move {
+                let _guard = lock.write_with("user:42", "job 1").await;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let waiter_id = TaskId::new();
+        let waiter = tokio::spawn(waiter_id.scope({
+            let lock = Arc::clone(&lock);
+            async move {
+                let _guard = lock.read("user:42").await;
+            }
+        }));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let info = lock.inspect(&"user:42");
+        assert_eq!(info.holders.len(), 1);
+        assert_eq!(info.holders[0].mode, LockMode::Write);
+        assert_eq!(info.holders[0].owner.as_deref(), Some("job 1"));
+        assert_eq!(info.holders[0].task, Some(holder_id));
+        assert!(info.holders[0].since.elapsed() >= Duration::from_millis(20));
+        #[cfg(feature = "backtrace")]
+        assert!(info.holders[0].backtrace.is_none());
+        assert_eq!(info.waiters.len(), 1);
+        assert_eq!(info.waiters[0].mode, LockMode::Read);
+        assert_eq!(info.waiters[0].owner, None);
+        assert_eq!(info.waiters[0].task, Some(waiter_id));
+
+        holder.await.unwrap();
+        waiter.await.unwrap();
+        assert!(lock.inspect(&"user:42").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_inspect_all() {
+        let lock = KeyRwLock::<_>::new();
+
+        let _foo = lock.read_with("foo", "first").await;
+        let _foo2 = lock.try_read_with("foo", "second").await.unwrap();
+        let bar = lock.upgradable_read("bar").await;
+
+        let mut all = lock.inspect_all();
+        all.sort_by_key(|(key, _)| *key);
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].0, "bar");
+        assert_eq!(all[0].1.holders[0].mode, LockMode::Read);
+        assert_eq!(all[1].0, "foo");
+        let owners = all[1]
+            .1
+            .holders
+            .iter()
+            .map(|holder| holder.owner.as_deref());
+        assert!(owners.eq([Some("first"), Some("second")]));
+
+        let _bar = bar.upgrade().await;
+        assert_eq!(lock.inspect(&"bar").holders[0].mode, LockMode::Write);
+    }
+
+    #[cfg(feature = "backtrace")]
+    #[tokio::test]
+    async fn test_capture_backtraces() {
+        let lock = KeyRwLock::<_>::builder().capture_backtraces(true).build();
+        let _foo = lock.read("foo").await;
+        assert!(lock.inspect(&"foo").holders[0].backtrace.is_some());
+    }
+}